@@ -1,260 +1,497 @@
-use core::fmt;
-
+use crate::exec::{call_unseen, exec_plain, indirect_callee, Operands, TaggedOperands};
+use crate::module::{func_type, host_call, Expr, FuncAddr, FuncInst, ModuleAddr, Store};
+use crate::trap::{instr_pos, Trap, TrapKind};
 use crate::types::*;
 
+/// One entry of the continuation, which is kept innermost last
 #[derive(Debug, Clone)]
-enum AdminInstr<T: Clone> {
-    Trap,
-    Plain(T, Box<Self>),
-    Label(usize, Vec<Val>, Option<T>, Box<Self>),
-    Breaking(usize, Vec<Val>, Box<Self>),
+enum Cont<T: Clone> {
+    Plain(T),
+    /// Arity, the operands below the block and the instrs a `br` continues with
+    Label(usize, Vec<Val>, Option<T>),
+    /// Return point of a call: result arity, the caller's operands and frame
+    Frame(usize, Vec<Val>, Activation),
+}
+
+/// What the next step does with the continuation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Control {
+    Run,
+    /// Unwinding to the `n`th enclosing label
+    Breaking(usize),
+    /// Unwinding to the enclosing frame
+    Returning,
 }
 
-#[derive(Debug)]
-struct Config<I: Clone>(AdminInstr<I>, Vec<Val>);
-
-macro_rules! impl_val(
-    ($fn_name:ident, $branch:ident ,$type:ty) => {
-         #[inline]
-         fn $fn_name(v: &mut Vec<Val>) -> $type {
-             if let Val::$branch(i) = v.pop().unwrap() {
-                 i
-             } else {
-                 panic!("type mismatch")
-             }
-         }
-    };
-);
-
-impl_val!(val_i32, I32, i32);
-impl_val!(val_f32, F32, f32);
-
-pub struct Instance {
-    pub types: Vec<Ty>,
-    pub locals: Vec<Val>,
+/// Activation of a function. The default frame stands for the embedder
+/// before the first call.
+#[derive(Debug, Clone, Default)]
+struct Activation {
+    func: FuncAddr,
+    module: ModuleAddr,
+    /// Params followed by the declared locals
+    locals: Vec<Val>,
 }
 
-impl Instance {
-    pub fn new() -> Self {
+/// Execution is finished once the continuation is empty
+#[derive(Debug, Clone)]
+struct Config<T: Clone> {
+    control: Control,
+    k: Vec<Cont<T>>,
+    vs: Vec<Val>,
+    frame: Activation,
+}
+
+pub struct Instance<'a> {
+    /// Snapshot of `Store::funcs` (see `exec`), the continuation borrows
+    /// bodies from
+    funcs: &'a [FuncInst],
+    /// `Store::call_depth` on entry
+    base: usize,
+    /// Frames in use, including `base`
+    depth: usize,
+    /// Emptied operand stacks and locals, reused so that steps don't allocate
+    spare: Vec<Vec<Val>>,
+}
+
+impl<'a> Instance<'a> {
+    pub fn new(funcs: &'a [FuncInst]) -> Self {
         Self {
-            types: vec![],
-            locals: vec![],
+            funcs,
+            base: 0,
+            depth: 0,
+            spare: vec![],
         }
     }
 
-    pub fn run(&mut self, instrs: &Vec<Instr>) -> Vec<Val> {
-        use AdminInstr::*;
-        let instr = Plain(&instrs[..], Box::new(Trap));
-        let mut config = Config(instr, vec![]);
-        let stop_fn = |instr: &AdminInstr<_>| if let Trap = instr { true } else { false };
-        while !stop_fn(&config.0) {
-            // print_config(&config);
-            config = self.step(config);
+    pub fn invoke(
+        &mut self,
+        store: &mut Store,
+        addr: FuncAddr,
+        args: Vec<Val>,
+    ) -> Result<Vec<Val>, Trap> {
+        self.base = store.call_depth;
+        self.depth = self.base;
+        let mut config = Config {
+            control: Control::Run,
+            k: vec![],
+            vs: args,
+            frame: Activation::default(),
+        };
+        self.call(
+            store,
+            addr,
+            &mut config.k,
+            &mut config.vs,
+            &mut config.frame,
+        )
+        .map_err(|kind| Trap::new(kind, Some(addr), vec![]))?;
+        while !config.k.is_empty() {
+            let func = config.frame.func;
+            self.step(store, &mut config).map_err(|(kind, e)| {
+                let pos = match e {
+                    Some(e) => instr_pos(&self.body(func).instrs, e).unwrap_or_default(),
+                    None => vec![],
+                };
+                Trap::new(kind, Some(func), pos)
+            })?;
         }
-        config.1
+        Ok(config.vs)
     }
 
-    fn step<'a>(&mut self, config: Config<&'a [Instr]>) -> Config<&'a [Instr]> {
-        let Config(instr, mut vs) = config;
-        use AdminInstr::*;
-        let instr: AdminInstr<&'a [Instr]> = match instr {
-            Trap => panic!("unreachable"),
-            Plain(es, k) => match es {
-                [] => *k,
-                _ => {
-                    let e = &es[0];
-                    let es_next = &es[1..];
-                    let k = if !es_next.is_empty() {
-                        Plain(es_next, k)
-                    } else {
-                        *k
-                    };
-                    match e {
-                        Instr::Unreachable => Trap,
-                        Instr::Nop => k,
-                        Instr::Drop => {
-                            todo!("drop instruction")
-                        }
-                        &Instr::I32Const(i) => {
-                            vs.push(Val::I32(i));
-                            k
-                        }
-                        Instr::I32Add => {
-                            let a = val_i32(&mut vs);
-                            let b = val_i32(&mut vs);
-                            vs.push(Val::I32(a + b));
-                            k
-                        }
-                        Instr::I32Sub => {
-                            todo!("i32.sub instruction")
-                        }
-                        Instr::I32Mul => {
-                            todo!("i32.mul instruction")
-                        }
-                        Instr::I32Eq => {
-                            let a = val_i32(&mut vs);
-                            let b = val_i32(&mut vs);
-                            vs.push(Val::I32(if a == b { 1 } else { 0 }));
-                            k
-                        }
-                        &Instr::LocalTee(i) => {
-                            self.locals[i] = vs.last().unwrap().clone();
-                            k
-                        }
-                        &Instr::LocalSet(i) => {
-                            self.locals[i] = vs.pop().unwrap().clone();
-                            k
-                        }
-                        &Instr::LocalGet(i) => {
-                            vs.push(self.locals[i].clone());
-                            k
-                        }
-                        &Instr::Br(n) => {
-                            let vs = std::mem::replace(&mut vs, Vec::new());
-                            Breaking(n, vs, Box::new(k))
-                        }
-                        Instr::If(bt, es_then, es_else) => {
-                            let (n_args, n_res) = {
-                                let bt = block_type(&self.types, bt);
-                                let (args_ty, res_ty) = bt.func_tys();
-                                (args_ty.len(), res_ty.len())
-                            };
-                            let i = val_i32(&mut vs);
-                            let vs = vs.drain(..vs.len() - n_args).collect::<Vec<_>>();
-                            let k = Label(n_res, vs, None, Box::new(k));
-                            if i != 0 {
-                                Plain(&es_then[..], Box::new(k))
-                            } else {
-                                Plain(&es_else[..], Box::new(k))
-                            }
-                        }
-                        Instr::Loop(bt, es_loop) => {
-                            let (n_args, _) = {
-                                let bt = block_type(&self.types, bt);
-                                let (args_ty, res_ty) = bt.func_tys();
-                                (args_ty.len(), res_ty.len())
-                            };
-                            let vs = vs.drain(..vs.len() - n_args).collect::<Vec<_>>();
-                            let k = Box::new(Label(n_args, vs, Some(&es[..1]), Box::new(k)));
-                            Plain(&es_loop[..], k)
-                        }
-                        Instr::Block(bt, es) => {
-                            let (n_args, n_res) = {
-                                let bt = block_type(&self.types, bt);
-                                let (args_ty, res_ty) = bt.func_tys();
-                                (args_ty.len(), res_ty.len())
-                            };
-                            let vs = vs.drain(..vs.len() - n_args).collect::<Vec<_>>();
-                            let k = Box::new(Label(n_res, vs, None, Box::new(k)));
-                            Plain(&es[..], k)
-                        }
+    /// Reduce one step. On a trap, returns the trapping instr with the kind.
+    fn step(
+        &mut self,
+        store: &mut Store,
+        config: &mut Config<&'a [Instr]>,
+    ) -> Result<(), (TrapKind, Option<&'a Instr>)> {
+        use Cont::*;
+        let Config {
+            control,
+            k,
+            vs,
+            frame,
+        } = config;
+        let top = k.pop().expect("stepping a halted config");
+        match (*control, top) {
+            (Control::Run, Plain(es)) => {
+                if let [e, es_next @ ..] = es {
+                    if !es_next.is_empty() {
+                        k.push(Plain(es_next));
                     }
+                    *control = self
+                        .exec(store, e, k, vs, frame)
+                        .map_err(|kind| (kind, Some(e)))?;
                 }
-            },
-            Label(_, mut vs2, _, k) => {
-                vs2.append(&mut vs);
-                vs = vs2;
-                *k
             }
-            Breaking(i, vs1, k) => match *k {
-                Plain(_, k) => Breaking(i, vs1, k),
-                Label(n, mut vs2, br, k) => {
-                    if i == 0 {
-                        vs2.extend_from_slice(&vs1[vs1.len() - n..]);
-                        vs = vs2;
-                        match br {
-                            None => *k,
-                            Some(es) => Plain(es, k),
-                        }
-                    } else {
-                        Breaking(i - 1, vs1, k)
+            (Control::Run, Label(_, mut vs2, _)) => {
+                vs2.append(vs);
+                self.recycle(std::mem::replace(vs, vs2));
+            }
+            (Control::Run, Frame(n, vs2, caller)) => {
+                // the body fell through, leaving its results on top
+                if vs.len() < n {
+                    return Err((TrapKind::StackUnderflow, None));
+                }
+                self.leave(n, vs2, vs, caller, frame);
+            }
+            (Control::Breaking(_), Plain(_)) => {}
+            (Control::Breaking(i), Label(n, mut vs2, br)) => {
+                if i == 0 {
+                    // arity was checked when the br was executed
+                    vs2.extend_from_slice(&vs[vs.len() - n..]);
+                    self.recycle(std::mem::replace(vs, vs2));
+                    if let Some(es) = br {
+                        k.push(Plain(es));
                     }
+                    *control = Control::Run;
+                } else {
+                    self.recycle(vs2);
+                    *control = Control::Breaking(i - 1);
                 }
-                _ => panic!("unreachable"),
-            },
-        };
-        Config(instr, vs)
+            }
+            (Control::Breaking(_), Frame(..)) => {
+                unreachable!("label depth was checked when the br was executed")
+            }
+            (Control::Returning, Plain(_)) => {}
+            (Control::Returning, Label(_, vs2, _)) => self.recycle(vs2),
+            (Control::Returning, Frame(n, vs2, caller)) => {
+                // arity was checked when the return was executed
+                self.leave(n, vs2, vs, caller, frame);
+                *control = Control::Run;
+            }
+        }
+        Ok(())
     }
-}
 
-fn indent(mut i: usize) {
-    while i > 0 {
-        print!("  ");
-        i -= 1;
+    /// Execute plain instr `e` with continuation `k`, returning how to go on.
+    fn exec(
+        &mut self,
+        store: &mut Store,
+        e: &'a Instr,
+        k: &mut Vec<Cont<&'a [Instr]>>,
+        vs: &mut Vec<Val>,
+        frame: &mut Activation,
+    ) -> Result<Control, TrapKind> {
+        use Cont::*;
+        match e {
+            Instr::Unreachable => return Err(TrapKind::Unreachable),
+            Instr::Nop => {}
+            &Instr::LocalTee(i) => {
+                let v = vs.last().ok_or(TrapKind::StackUnderflow)?.clone();
+                *local(frame, i)? = v;
+            }
+            &Instr::LocalSet(i) => {
+                let v = vs.pop_val()?;
+                *local(frame, i)? = v;
+            }
+            &Instr::LocalGet(i) => vs.push(local(frame, i)?.clone()),
+            &Instr::Br(n) => return br(n, k, vs),
+            &Instr::BrIf(n) => {
+                if vs.pop_i32()? != 0 {
+                    return br(n, k, vs);
+                }
+            }
+            Instr::BrTable(ns, default) => {
+                let i = vs.pop_i32()? as u32 as usize;
+                return br(*ns.get(i).unwrap_or(default), k, vs);
+            }
+            Instr::Return => {
+                if vs.len() < self.func_type(frame.func).results.len() {
+                    return Err(TrapKind::StackUnderflow);
+                }
+                return Ok(Control::Returning);
+            }
+            &Instr::Call(i) => {
+                let addr = store.modules[frame.module as usize].func_addrs[i];
+                self.call(store, addr, k, vs, frame)?;
+            }
+            &Instr::CallIndirect(x, y) => {
+                let i = vs.pop_i32()? as u32;
+                let addr = indirect_callee(store, frame.module, x, y, i)?;
+                self.call(store, addr, k, vs, frame)?;
+            }
+            Instr::If(bt, es_then, es_else) => {
+                let (n_args, n_res) = {
+                    let bt = block_type(&store.modules[frame.module as usize].types, bt);
+                    (bt.params.len(), bt.results.len())
+                };
+                let i = vs.pop_i32()?;
+                let vs2 = self.split_args(vs, n_args)?;
+                k.push(Label(n_res, vs2, None));
+                k.push(Plain(if i != 0 { &es_then[..] } else { &es_else[..] }));
+            }
+            Instr::Loop(bt, es_loop) => {
+                let n_args = block_type(&store.modules[frame.module as usize].types, bt)
+                    .params
+                    .len();
+                let vs2 = self.split_args(vs, n_args)?;
+                k.push(Label(n_args, vs2, Some(std::slice::from_ref(e))));
+                k.push(Plain(&es_loop[..]));
+            }
+            Instr::Block(bt, es) => {
+                let (n_args, n_res) = {
+                    let bt = block_type(&store.modules[frame.module as usize].types, bt);
+                    (bt.params.len(), bt.results.len())
+                };
+                let vs2 = self.split_args(vs, n_args)?;
+                k.push(Label(n_res, vs2, None));
+                k.push(Plain(&es[..]));
+            }
+            instr => exec_plain(instr, store, frame.module, vs)?,
+        }
+        Ok(Control::Run)
     }
-}
 
-fn print_cont<T: Clone + fmt::Debug>(k: &AdminInstr<T>, n: usize) {
-    match k {
-        AdminInstr::Trap => {
-            indent(n);
-            println!("trap")
+    /// Enter function `addr` by pushing its return point and body onto `k`,
+    /// making its frame current and leaving only the args on `vs`. Host
+    /// functions run to completion right away.
+    fn call(
+        &mut self,
+        store: &mut Store,
+        addr: FuncAddr,
+        k: &mut Vec<Cont<&'a [Instr]>>,
+        vs: &mut Vec<Val>,
+        frame: &mut Activation,
+    ) -> Result<(), TrapKind> {
+        use Cont::*;
+        if self.depth >= store.max_call_depth {
+            return Err(TrapKind::StackExhausted);
         }
-        AdminInstr::Plain(es, k) => {
-            indent(n);
-            println!("plain {:?}", es);
-            print_cont(k, n);
+        let funcs = self.funcs;
+        let Some(func) = funcs.get(addr as usize) else {
+            let args = vs.pop_vals(func_type(store, addr).params.len())?;
+            let results = call_unseen(store, self.depth, args, |funcs, store, args| {
+                Instance::new(funcs).invoke(store, addr, args)
+            })?;
+            vs.extend(results);
+            return Ok(());
+        };
+        match func {
+            FuncInst::Local {
+                func_type,
+                module,
+                code,
+            } => {
+                let n_args = func_type.params.len();
+                let vs2 = self.split_args(vs, n_args)?;
+                let fresh = self.fresh();
+                let mut locals = std::mem::replace(vs, fresh);
+                locals.extend(code.locals.iter().map(Val::default_for));
+                let callee = Activation {
+                    func: addr,
+                    module: *module,
+                    locals,
+                };
+                let caller = std::mem::replace(frame, callee);
+                self.depth += 1;
+                let n_res = func_type.results.len();
+                k.push(Frame(n_res, vs2, caller));
+                // the body is a block, `br` to it returns
+                let vs3 = self.fresh();
+                k.push(Label(n_res, vs3, None));
+                k.push(Plain(&code.body.instrs[..]));
+            }
+            FuncInst::Host { func_type, .. } => {
+                let args = vs.pop_vals(func_type.params.len())?;
+                // the default frame is the embedder's
+                let module = (self.depth > self.base).then_some(frame.module);
+                vs.extend(host_call(store, addr, module, self.depth + 1, &args)?);
+            }
         }
-        AdminInstr::Label(i, vs, es, k) => {
-            indent(n);
-            println!("label");
-            indent(n + 1);
-            println!("vs: {:?}", vs);
-            indent(n + 1);
-            println!("es: {:?}", es);
-            print_cont(k, n);
+        Ok(())
+    }
+
+    /// Pop the current frame, making `vs` the caller's operands `vs2` with the
+    /// top `n` of `vs` pushed.
+    fn leave(
+        &mut self,
+        n: usize,
+        mut vs2: Vec<Val>,
+        vs: &mut Vec<Val>,
+        caller: Activation,
+        frame: &mut Activation,
+    ) {
+        vs2.extend_from_slice(&vs[vs.len() - n..]);
+        self.recycle(std::mem::replace(vs, vs2));
+        let callee = std::mem::replace(frame, caller);
+        self.recycle(callee.locals);
+        self.depth -= 1;
+    }
+
+    /// Split the operand stack at a block entry, leaving the block's `n_args`
+    /// arguments in `vs` and returning the values below them.
+    fn split_args(&mut self, vs: &mut Vec<Val>, n_args: usize) -> Result<Vec<Val>, TrapKind> {
+        if vs.len() < n_args {
+            return Err(TrapKind::StackUnderflow);
+        }
+        let mut below = self.fresh();
+        below.extend(vs.drain(..vs.len() - n_args));
+        Ok(below)
+    }
+
+    fn fresh(&mut self) -> Vec<Val> {
+        self.spare.pop().unwrap_or_default()
+    }
+
+    fn recycle(&mut self, mut vs: Vec<Val>) {
+        vs.clear();
+        self.spare.push(vs);
+    }
+
+    fn func_type(&self, addr: FuncAddr) -> &'a FuncType {
+        let funcs = self.funcs;
+        match &funcs[addr as usize] {
+            FuncInst::Local { func_type, .. } | FuncInst::Host { func_type, .. } => func_type,
         }
-        AdminInstr::Breaking(i, vs, k) => {
-            indent(n);
-            println!("break {}", i);
-            indent(n + 1);
-            println!("vs: {:?}", vs);
-            print_cont(k, n);
+    }
+
+    fn body(&self, addr: FuncAddr) -> &'a Expr {
+        let funcs = self.funcs;
+        match &funcs[addr as usize] {
+            FuncInst::Local { code, .. } => &code.body,
+            FuncInst::Host { .. } => unreachable!("host functions have no body"),
         }
     }
 }
 
-fn print_config<T: Clone + fmt::Debug>(config: &Config<T>) {
-    let Config(k, vs) = config;
-    println!(">>> CONFIG DUMP");
-    println!("value stack: {:?}", vs);
-    println!("continuation:");
-    print_cont(k, 0)
+fn local(frame: &mut Activation, i: usize) -> Result<&mut Val, TrapKind> {
+    frame.locals.get_mut(i).ok_or(TrapKind::UnknownLocal)
+}
+
+/// Start breaking out to the `n`th enclosing label of continuation `k`.
+fn br<T: Clone>(n: usize, k: &[Cont<T>], vs: &[Val]) -> Result<Control, TrapKind> {
+    let arity = label_arity(k, n).ok_or(TrapKind::UnknownLabel)?;
+    if vs.len() < arity {
+        return Err(TrapKind::StackUnderflow);
+    }
+    Ok(Control::Breaking(n))
+}
+
+/// Arity of the `n`th enclosing label of continuation `k`.
+fn label_arity<T: Clone>(k: &[Cont<T>], mut n: usize) -> Option<usize> {
+    for c in k.iter().rev() {
+        match c {
+            // labels don't reach across calls
+            Cont::Frame(..) => return None,
+            Cont::Plain(_) => {}
+            Cont::Label(arity, _, _) => {
+                if n == 0 {
+                    return Some(*arity);
+                }
+                n -= 1;
+            }
+        }
+    }
+    None
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::module::testing::{call_indirect_store, host_store, loop_store, store_with_funcs};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Run `body` as a function `[] -> results` with declared `locals`.
+    fn run(
+        results: Vec<ValType>,
+        locals: Vec<ValType>,
+        body: Vec<Instr>,
+    ) -> Result<Vec<Val>, Trap> {
+        let ty = FuncType {
+            params: vec![],
+            results,
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, locals, body)]);
+        let funcs = store.funcs.clone();
+        Instance::new(&funcs).invoke(&mut store, 0, vec![])
+    }
 
     #[test]
     pub fn test() {
-        let mut vm = Instance::new();
-        let result = vm
-            .run(&vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add])
-            .last()
-            .cloned();
-        assert_eq!(Some(Val::I32(2)), result);
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
+        );
+        assert_eq!(Ok(vec![Val::I32(2)]), result);
     }
 
     #[test]
     pub fn test_block() {
-        let mut vm = Instance::new();
-        let result = vm
-            .run(&vec![Instr::Block(
-                BlockType::ValTy(Ty::I32),
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::Block(
+                BlockType::ValTy(ValType::I32),
                 vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
-            )])
-            .last()
-            .cloned();
-        assert_eq!(Some(Val::I32(2)), result);
+            )],
+        );
+        assert_eq!(Ok(vec![Val::I32(2)]), result);
+    }
+
+    #[test]
+    pub fn test_block_type_index() {
+        // type section: [] -> [i32]
+        let (_, types) =
+            crate::binary::sections::parse_type_section(&[0x01, 0x60, 0x00, 0x01, 0x7F]).unwrap();
+        let body = vec![Instr::Block(
+            BlockType::Index(0),
+            vec![Instr::I32Const(2), Instr::I32Const(3), Instr::I32Add],
+        )];
+        let mut store = store_with_funcs(types, vec![(0, vec![], body)]);
+        let funcs = store.funcs.clone();
+        let result = Instance::new(&funcs).invoke(&mut store, 0, vec![]);
+        assert_eq!(Ok(vec![Val::I32(5)]), result);
+    }
+
+    #[test]
+    pub fn test_ref_is_null() {
+        let result = run(
+            vec![ValType::I32],
+            vec![ValType::ExternRef],
+            vec![Instr::LocalGet(0), Instr::RefIsNull],
+        );
+        assert_eq!(Ok(vec![Val::I32(1)]), result);
+    }
+
+    #[test]
+    pub fn test_unreachable() {
+        let result = run(
+            vec![],
+            vec![],
+            vec![
+                Instr::I32Const(1),
+                Instr::Block(BlockType::Empty, vec![Instr::Nop, Instr::Unreachable]),
+            ],
+        );
+        assert_eq!(
+            Err(Trap::new(TrapKind::Unreachable, Some(0), vec![1, 1])),
+            result
+        );
+    }
+
+    #[test]
+    pub fn test_br_unknown_label() {
+        // depth 1 is the function body, 2 would leave the function
+        let result = run(
+            vec![],
+            vec![],
+            vec![Instr::Block(BlockType::Empty, vec![Instr::Br(2)])],
+        );
+        assert_eq!(
+            Err(Trap::new(TrapKind::UnknownLabel, Some(0), vec![0, 0])),
+            result
+        );
     }
 
     #[test]
     pub fn test_loop() {
-        let mut vm = Instance::new();
-        vm.locals.push(Val::I32(0));
-        let result = vm
-            .run(&vec![
+        let result = run(
+            vec![ValType::I32],
+            vec![ValType::I32],
+            vec![
                 Instr::Block(
                     BlockType::Empty,
                     vec![Instr::Loop(
@@ -271,9 +508,166 @@ mod tests {
                     )],
                 ),
                 Instr::LocalGet(0),
-            ])
-            .last()
-            .cloned();
-        assert_eq!(Some(Val::I32(20)), result);
+            ],
+        );
+        assert_eq!(Ok(vec![Val::I32(20)]), result);
+    }
+
+    #[test]
+    pub fn test_steps_reuse_stacks() {
+        let mut store = loop_store();
+        let funcs = store.funcs.clone();
+        let mut vm = Instance::new(&funcs);
+        let result = vm.invoke(&mut store, 1, vec![Val::I32(1000)]);
+        assert_eq!(Ok(vec![Val::I32(1000)]), result);
+        // one stack per label or frame that was live at once, not per iteration
+        assert_eq!(8, vm.spare.len());
+        let result = vm.invoke(&mut store, 2, vec![Val::I32(10)]);
+        assert_eq!(Ok(vec![Val::I32(55)]), result);
+        assert!(vm.spare.len() < 64);
+    }
+
+    #[test]
+    pub fn test_call_recursive() {
+        let types = vec![FuncType {
+            params: vec![ValType::I64],
+            results: vec![ValType::I64],
+        }];
+        let fac = vec![
+            Instr::LocalGet(0),
+            Instr::I64Eqz,
+            Instr::If(
+                BlockType::ValTy(ValType::I64),
+                vec![Instr::I64Const(1)],
+                vec![
+                    Instr::LocalGet(0),
+                    Instr::LocalGet(0),
+                    Instr::I64Const(1),
+                    Instr::I64Sub,
+                    Instr::Call(0),
+                    Instr::I64Mul,
+                ],
+            ),
+        ];
+        let mut store = store_with_funcs(types, vec![(0, vec![], fac)]);
+        let funcs = store.funcs.clone();
+        let result = Instance::new(&funcs).invoke(&mut store, 0, vec![Val::I64(20)]);
+        assert_eq!(Ok(vec![Val::I64(2432902008176640000)]), result);
+    }
+
+    #[test]
+    pub fn test_call_keeps_caller_operands() {
+        let types = vec![
+            FuncType {
+                params: vec![],
+                results: vec![ValType::I32, ValType::I32],
+            },
+            FuncType {
+                params: vec![ValType::I32],
+                results: vec![ValType::I32],
+            },
+        ];
+        let main = vec![
+            Instr::I32Const(7),
+            Instr::LocalSet(0),
+            Instr::LocalGet(0),
+            Instr::I32Const(5),
+            Instr::Call(1),
+        ];
+        let double = vec![
+            Instr::LocalGet(0),
+            Instr::LocalGet(0),
+            Instr::I32Add,
+            Instr::LocalSet(1),
+            Instr::LocalGet(1),
+        ];
+        let mut store = store_with_funcs(
+            types,
+            vec![
+                (0, vec![ValType::I32], main),
+                (1, vec![ValType::I32], double),
+            ],
+        );
+        let funcs = store.funcs.clone();
+        let result = Instance::new(&funcs).invoke(&mut store, 0, vec![]);
+        assert_eq!(Ok(vec![Val::I32(7), Val::I32(10)]), result);
+    }
+
+    #[test]
+    pub fn test_return() {
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![
+                Instr::I32Const(1),
+                Instr::Block(
+                    BlockType::Empty,
+                    vec![Instr::I32Const(2), Instr::I32Const(3), Instr::Return],
+                ),
+                Instr::Unreachable,
+            ],
+        );
+        assert_eq!(Ok(vec![Val::I32(3)]), result);
+    }
+
+    #[test]
+    pub fn test_call_stack_exhausted() {
+        let ty = FuncType {
+            params: vec![],
+            results: vec![],
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, vec![], vec![Instr::Call(0)])]);
+        let funcs = store.funcs.clone();
+        let mut vm = Instance::new(&funcs);
+        store.max_call_depth = 100;
+        let result = vm.invoke(&mut store, 0, vec![]);
+        assert_eq!(
+            Err(Trap::new(TrapKind::StackExhausted, Some(0), vec![0])),
+            result
+        );
+    }
+
+    #[test]
+    pub fn test_call_indirect() {
+        let mut store = call_indirect_store();
+        let funcs = store.funcs.clone();
+        let mut dispatch = |i| Instance::new(&funcs).invoke(&mut store, 0, vec![Val::I32(i)]);
+        // the callee's type is equal to the expected one, though declared at another index
+        assert_eq!(Ok(vec![Val::I32(20)]), dispatch(0));
+        let trap = |kind| Err(Trap::new(kind, Some(0), vec![2]));
+        assert_eq!(trap(TrapKind::IndirectCallTypeMismatch), dispatch(1));
+        assert_eq!(trap(TrapKind::UninitializedElement), dispatch(2));
+        assert_eq!(trap(TrapKind::UndefinedElement), dispatch(3));
+        assert_eq!(trap(TrapKind::UndefinedElement), dispatch(-1));
+    }
+
+    #[test]
+    pub fn test_host_call() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut store = host_store(log.clone());
+        let funcs = store.funcs.clone();
+        let result = Instance::new(&funcs).invoke(&mut store, 3, vec![]);
+        assert_eq!(Ok(vec![Val::I32(11)]), result);
+        assert_eq!(vec!["hello".to_string()], *log.borrow());
+        // the trap is reported at the call
+        let result = Instance::new(&funcs).invoke(&mut store, 4, vec![]);
+        assert_eq!(
+            Err(Trap::new(
+                TrapKind::Host("boom".to_string()),
+                Some(4),
+                vec![1]
+            )),
+            result
+        );
+        // called directly, without a calling instance
+        let result = Instance::new(&funcs).invoke(&mut store, 2, vec![]);
+        assert_eq!(
+            Err(Trap::new(
+                TrapKind::Host("boom".to_string()),
+                Some(2),
+                vec![]
+            )),
+            result
+        );
     }
 }
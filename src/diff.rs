@@ -0,0 +1,226 @@
+//! Differential execution: running a function on the VM, `cont::Instance`
+//! and `flat::FlatVM` and checking that they agree.
+
+use crate::cont;
+use crate::flat::FlatVM;
+use crate::module::{FuncAddr, Store};
+use crate::trap::Trap;
+use crate::types::Val;
+use crate::VM;
+
+/// Run function `addr` on all engines, each on its own copy of `store`, and
+/// panic if their results, traps or final stores differ from those of
+/// `FlatVM`, the engine behind `func_invoke`. `store` is left as `FlatVM`
+/// left it. Host functions are called once by each engine, so their side
+/// effects outside the store happen several times.
+pub fn diff_invoke(store: &mut Store, addr: FuncAddr, args: Vec<Val>) -> Result<Vec<Val>, Trap> {
+    let funcs = store.funcs.clone();
+    let mut vm_store = store.clone();
+    let vm_result = VM::new(&funcs).invoke(&mut vm_store, addr, args.clone());
+    let mut cont_store = store.clone();
+    let cont_result = cont::Instance::new(&funcs).invoke(&mut cont_store, addr, args.clone());
+    let flat_result = FlatVM::new(&funcs).invoke(store, addr, args);
+
+    for (engine, result, other) in [
+        ("vm", vm_result, vm_store),
+        ("cont", cont_result, cont_store),
+    ] {
+        let same_result = match (&flat_result, &result) {
+            (Ok(a), Ok(b)) => a.len() == b.len() && a.iter().zip(b).all(|(a, b)| same_val(a, b)),
+            (Err(a), Err(b)) => a == b,
+            _ => false,
+        };
+        if !same_result {
+            panic!(
+                "engines disagree on func {}: flat {:?}, {} {:?}",
+                addr, flat_result, engine, result
+            );
+        }
+        if let Some(diff) = store_diff(store, &other) {
+            panic!(
+                "engines disagree on func {}: {} (flat vs {})",
+                addr, diff, engine
+            );
+        }
+    }
+    flat_result
+}
+
+/// Equality with NaNs compared by their bits
+fn same_val(a: &Val, b: &Val) -> bool {
+    match (a, b) {
+        (Val::F32(a), Val::F32(b)) => a.to_bits() == b.to_bits(),
+        (Val::F64(a), Val::F64(b)) => a.to_bits() == b.to_bits(),
+        _ => a == b,
+    }
+}
+
+/// The first difference in the state of two stores, if any
+pub fn store_diff(a: &Store, b: &Store) -> Option<String> {
+    for (what, x, y) in [
+        ("memories", a.mems.len(), b.mems.len()),
+        ("globals", a.globals.len(), b.globals.len()),
+        ("tables", a.tables.len(), b.tables.len()),
+        ("elems", a.elems.len(), b.elems.len()),
+        ("datas", a.datas.len(), b.datas.len()),
+    ] {
+        if x != y {
+            return Some(format!("{} {} vs {}", x, what, y));
+        }
+    }
+    for (i, (a, b)) in a.mems.iter().zip(&b.mems).enumerate() {
+        if a.mem_type != b.mem_type {
+            return Some(format!(
+                "memory {} has type {:?} vs {:?}",
+                i, a.mem_type, b.mem_type
+            ));
+        }
+        if a.data.len() != b.data.len() {
+            return Some(format!(
+                "memory {} has size {} vs {}",
+                i,
+                a.data.len(),
+                b.data.len()
+            ));
+        }
+        if let Some(at) = a.data.iter().zip(&b.data).position(|(x, y)| x != y) {
+            return Some(format!(
+                "memory {} differs at {}: {} vs {}",
+                i, at, a.data[at], b.data[at]
+            ));
+        }
+    }
+    for (i, (a, b)) in a.globals.iter().zip(&b.globals).enumerate() {
+        if !same_val(&a.value, &b.value) {
+            return Some(format!("global {} is {:?} vs {:?}", i, a.value, b.value));
+        }
+    }
+    for (i, (a, b)) in a.tables.iter().zip(&b.tables).enumerate() {
+        if a.table_type != b.table_type {
+            return Some(format!(
+                "table {} has type {:?} vs {:?}",
+                i, a.table_type, b.table_type
+            ));
+        }
+        if a.elem != b.elem {
+            return Some(format!("table {} is {:?} vs {:?}", i, a.elem, b.elem));
+        }
+    }
+    for (i, (a, b)) in a.elems.iter().zip(&b.elems).enumerate() {
+        if a.elem != b.elem {
+            return Some(format!("elem {} is {:?} vs {:?}", i, a.elem, b.elem));
+        }
+    }
+    for (i, (a, b)) in a.datas.iter().zip(&b.datas).enumerate() {
+        if a.data != b.data {
+            return Some(format!("data {} is {:?} vs {:?}", i, a.data, b.data));
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::*;
+    use crate::module::{func_invoke, DataInst, Ref};
+    use crate::trap::TrapKind;
+    use crate::types::{FuncType, ValType};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_diff_control_cases() {
+        for (name, results, body, expected) in control_cases() {
+            let ty = FuncType {
+                params: vec![],
+                results,
+            };
+            let mut store = store_with_funcs(vec![ty], vec![(0, vec![ValType::I32], body)]);
+            assert_eq!(Ok(expected), diff_invoke(&mut store, 0, vec![]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_diff_multi_value_cases() {
+        for (name, body, expected) in multi_value_cases() {
+            let locals = vec![ValType::I32; 2];
+            let mut store = store_with_funcs(multi_value_types(), vec![(0, locals, body)]);
+            assert_eq!(Ok(expected), diff_invoke(&mut store, 0, vec![]), "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_diff_traps() {
+        let mut store = call_indirect_store();
+        let mut dispatch = |i| diff_invoke(&mut store, 0, vec![Val::I32(i)]);
+        assert_eq!(Ok(vec![Val::I32(20)]), dispatch(0));
+        for (i, kind) in [
+            (1, TrapKind::IndirectCallTypeMismatch),
+            (2, TrapKind::UninitializedElement),
+            (3, TrapKind::UndefinedElement),
+        ] {
+            assert_eq!(Err(Trap::new(kind, Some(0), vec![2])), dispatch(i));
+        }
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut store = host_store(log.clone());
+        assert_eq!(Ok(vec![Val::I32(11)]), diff_invoke(&mut store, 3, vec![]));
+        // once per engine
+        assert_eq!(3, log.borrow().len());
+        assert!(diff_invoke(&mut store, 4, vec![]).is_err());
+
+        // embedder calls go through both engines too
+        store.cross_check = true;
+        assert_eq!(Ok(vec![Val::I32(21)]), func_invoke(&mut store, 3, &[]));
+        assert_eq!(6, log.borrow().len());
+    }
+
+    #[test]
+    fn test_store_diff() {
+        let a = host_store(Rc::default());
+        let mut b = a.clone();
+        assert_eq!(None, store_diff(&a, &b));
+        b.globals[0].value = Val::I32(2);
+        assert_eq!(
+            Some("global 0 is I32(1) vs I32(2)".to_string()),
+            store_diff(&a, &b)
+        );
+        let mut b = a.clone();
+        b.mems[0].data[3] = 0;
+        assert_eq!(
+            Some("memory 0 differs at 3: 108 vs 0".to_string()),
+            store_diff(&a, &b)
+        );
+
+        let mut a = store_with_funcs(vec![], vec![]);
+        with_table(&mut a, vec![Ref::Func(0)]);
+        let mut b = a.clone();
+        b.tables[0].elem[0] = Ref::Func(1);
+        assert!(store_diff(&a, &b).unwrap().starts_with("table 0"));
+        let mut b = a.clone();
+        b.tables[0].table_type.limits.max = Some(2);
+        assert!(store_diff(&a, &b).unwrap().starts_with("table 0 has type"));
+
+        // stores differing only in how many instances they hold
+        let mut b = a.clone();
+        b.tables.push(a.tables[0].clone());
+        assert_eq!(Some("1 tables vs 2".to_string()), store_diff(&a, &b));
+        let mut b = a.clone();
+        b.datas.push(DataInst { data: vec![] });
+        assert_eq!(Some("0 datas vs 1".to_string()), store_diff(&a, &b));
+    }
+
+    #[test]
+    #[should_panic(expected = "engines disagree on func 0")]
+    fn test_diff_panics() {
+        // a host function answering differently the second time it's called
+        let mut store = store_with_funcs(vec![], vec![]);
+        let calls = Rc::new(RefCell::new(0));
+        crate::typed::func_wrap(&mut store, "count", move || {
+            *calls.borrow_mut() += 1;
+            *calls.borrow()
+        });
+        diff_invoke(&mut store, 0, vec![]).unwrap();
+    }
+}
@@ -0,0 +1,1588 @@
+// Semantics of the plain (non-control) instructions, shared by both
+// interpreters. Each engine keeps its own operand stack representation and
+// exposes it through `Operands`.
+//
+// An engine runs on a snapshot of `Store::funcs`, taken when the invocation
+// starts, so that it can borrow function bodies while host functions mutate
+// the store. A host function may invoke functions again: the nested
+// invocation starts `Store::call_depth` frames deep, the frames of those it is
+// nested in counting towards `Store::max_call_depth`. Functions a host
+// function allocates are missing from the snapshots taken before, and called
+// through `call_unseen`.
+
+use crate::module::{
+    func_type, global_read, global_write, mem_grow, mem_size, table_grow, DataAddr, ElemAddr,
+    FuncAddr, FuncInst, GlobalAddr, MemAddr, MemInst, ModuleAddr, Ref, Store, TableAddr,
+};
+use crate::trap::{Trap, TrapKind};
+use crate::types::*;
+
+/// Default of `Store::max_call_depth`.
+pub const DEFAULT_MAX_CALL_DEPTH: usize = 1024;
+
+/// Nesting of host functions and `call_unseen` allowed before trapping with
+/// `StackExhausted`. Unlike wasm calls, each one holds native stack while it
+/// runs.
+pub const MAX_HOST_NESTING: usize = 64;
+
+macro_rules! impl_typed_ops {
+    ($($type:ty, $push:ident, $pop:ident, $variant:ident),* $(,)?) => {
+        $(
+            #[inline]
+            fn $push(&mut self, v: $type) {
+                self.push(Val::$variant(v))
+            }
+
+            #[inline]
+            fn $pop(&mut self) -> Result<$type, TrapKind> {
+                match self.pop_val()? {
+                    Val::$variant(v) => Ok(v),
+                    _ => Err(TrapKind::TypeMismatch),
+                }
+            }
+        )*
+    };
+}
+
+/// What the plain instructions need of an operand stack. Every pop names the
+/// type it expects, so stacks of untagged slots can implement it too.
+pub trait Operands {
+    fn push_val(&mut self, v: Val);
+    fn push_i32(&mut self, v: i32);
+    fn pop_i32(&mut self) -> Result<i32, TrapKind>;
+    fn push_i64(&mut self, v: i64);
+    fn pop_i64(&mut self) -> Result<i64, TrapKind>;
+    fn push_f32(&mut self, v: f32);
+    fn pop_f32(&mut self) -> Result<f32, TrapKind>;
+    fn push_f64(&mut self, v: f64);
+    fn pop_f64(&mut self) -> Result<f64, TrapKind>;
+    fn push_ref(&mut self, r: Ref);
+    fn pop_ref(&mut self) -> Result<Ref, TrapKind>;
+
+    /// Pop a value of type `ty`
+    fn pop_typed(&mut self, ty: &ValType) -> Result<Val, TrapKind>;
+
+    /// Pop a value whatever its type
+    fn drop_val(&mut self) -> Result<(), TrapKind>;
+
+    /// Replace the top two values by the lower one if `first`, else by the top one
+    fn select_val(&mut self, first: bool) -> Result<(), TrapKind>;
+
+    #[inline]
+    fn push_bool(&mut self, b: bool) {
+        self.push_i32(b as i32)
+    }
+}
+
+/// Operand stacks whose values carry their type, which can be popped
+/// without knowing it.
+pub trait TaggedOperands: Operands {
+    fn pop_val(&mut self) -> Result<Val, TrapKind>;
+
+    /// Pop the top `n` values, keeping their stack order.
+    fn pop_vals(&mut self, n: usize) -> Result<Vec<Val>, TrapKind> {
+        let mut vals = (0..n)
+            .map(|_| self.pop_val())
+            .collect::<Result<Vec<_>, _>>()?;
+        vals.reverse();
+        Ok(vals)
+    }
+}
+
+impl Operands for Vec<Val> {
+    #[inline]
+    fn push_val(&mut self, v: Val) {
+        self.push(v)
+    }
+
+    impl_typed_ops!(
+        i32, push_i32, pop_i32, I32, i64, push_i64, pop_i64, I64, f32, push_f32, pop_f32, F32, f64,
+        push_f64, pop_f64, F64, Ref, push_ref, pop_ref, Ref,
+    );
+
+    fn pop_typed(&mut self, ty: &ValType) -> Result<Val, TrapKind> {
+        let v = self.pop_val()?;
+        if v.val_type() != *ty {
+            return Err(TrapKind::TypeMismatch);
+        }
+        Ok(v)
+    }
+
+    #[inline]
+    fn drop_val(&mut self) -> Result<(), TrapKind> {
+        self.pop_val().map(drop)
+    }
+
+    fn select_val(&mut self, first: bool) -> Result<(), TrapKind> {
+        let v2 = self.pop_val()?;
+        let v1 = self.pop_val()?;
+        self.push(if first { v1 } else { v2 });
+        Ok(())
+    }
+}
+
+impl TaggedOperands for Vec<Val> {
+    #[inline]
+    fn pop_val(&mut self) -> Result<Val, TrapKind> {
+        self.pop().ok_or(TrapKind::StackUnderflow)
+    }
+}
+
+macro_rules! unop {
+    ($vs:ident, $pop:ident, $push:ident, |$a:ident| $e:expr) => {{
+        let $a = $vs.$pop()?;
+        $vs.$push($e)
+    }};
+}
+
+macro_rules! binop {
+    ($vs:ident, $pop:ident, $push:ident, |$a:ident, $b:ident| $e:expr) => {{
+        let $b = $vs.$pop()?;
+        let $a = $vs.$pop()?;
+        $vs.$push($e)
+    }};
+}
+
+macro_rules! relop {
+    ($vs:ident, $pop:ident, |$a:ident, $b:ident| $e:expr) => {
+        binop!($vs, $pop, push_bool, |$a, $b| $e)
+    };
+}
+
+macro_rules! load {
+    ($vs:ident, $mem:expr, $arg:ident, $t:ty, $push:ident) => {{
+        let base = $vs.pop_i32()?;
+        let bytes = load_bytes($mem, base, $arg)?;
+        $vs.$push(<$t>::from_le_bytes(bytes))
+    }};
+    // narrow loads, extending to the pushed type as `$t` is signed or not
+    ($vs:ident, $mem:expr, $arg:ident, $t:ty, $push:ident, $ext:ty) => {{
+        let base = $vs.pop_i32()?;
+        let bytes = load_bytes($mem, base, $arg)?;
+        $vs.$push(<$t>::from_le_bytes(bytes) as $ext)
+    }};
+}
+
+macro_rules! store {
+    ($vs:ident, $mem:expr, $arg:ident, $pop:ident) => {{
+        let v = $vs.$pop()?;
+        let base = $vs.pop_i32()?;
+        store_bytes($mem, base, $arg, &v.to_le_bytes())?
+    }};
+    // narrow stores, wrapping the value to `$t`
+    ($vs:ident, $mem:expr, $arg:ident, $pop:ident, $t:ty) => {{
+        let v = $vs.$pop()? as $t;
+        let base = $vs.pop_i32()?;
+        store_bytes($mem, base, $arg, &v.to_le_bytes())?
+    }};
+}
+
+/// Execute a plain instruction that isn't handled by the interpreter itself,
+/// on behalf of module instance `module`.
+pub fn exec_plain(
+    instr: &Instr,
+    store: &mut Store,
+    module: ModuleAddr,
+    vs: &mut impl Operands,
+) -> Result<(), TrapKind> {
+    match instr {
+        Instr::Drop => vs.drop_val()?,
+        Instr::Select(_) => {
+            let c = vs.pop_i32()?;
+            vs.select_val(c != 0)?
+        }
+        Instr::RefNull(t) => vs.push_ref(Ref::Null(t.clone())),
+        Instr::RefIsNull => {
+            let r = vs.pop_ref()?;
+            vs.push_bool(r.is_null());
+        }
+        &Instr::RefFunc(x) => {
+            let addr = store.modules[module as usize].func_addrs[x];
+            vs.push_ref(Ref::Func(addr))
+        }
+        &Instr::GlobalGet(x) => {
+            let addr = global_addr(store, module, x);
+            vs.push_val(global_read(store, addr))
+        }
+        &Instr::GlobalSet(x) => {
+            let addr = global_addr(store, module, x);
+            let v = vs.pop_typed(&store.globals[addr as usize].global_type.value_type)?;
+            global_write(store, addr, v).map_err(|_| TrapKind::TypeMismatch)?
+        }
+        Instr::I32Load(arg) => load!(vs, mem0(store, module), arg, i32, push_i32),
+        Instr::I64Load(arg) => load!(vs, mem0(store, module), arg, i64, push_i64),
+        Instr::F32Load(arg) => load!(vs, mem0(store, module), arg, f32, push_f32),
+        Instr::F64Load(arg) => load!(vs, mem0(store, module), arg, f64, push_f64),
+        Instr::I32Load8S(arg) => load!(vs, mem0(store, module), arg, i8, push_i32, i32),
+        Instr::I32Load8U(arg) => load!(vs, mem0(store, module), arg, u8, push_i32, i32),
+        Instr::I32Load16S(arg) => load!(vs, mem0(store, module), arg, i16, push_i32, i32),
+        Instr::I32Load16U(arg) => load!(vs, mem0(store, module), arg, u16, push_i32, i32),
+        Instr::I64Load8S(arg) => load!(vs, mem0(store, module), arg, i8, push_i64, i64),
+        Instr::I64Load8U(arg) => load!(vs, mem0(store, module), arg, u8, push_i64, i64),
+        Instr::I64Load16S(arg) => load!(vs, mem0(store, module), arg, i16, push_i64, i64),
+        Instr::I64Load16U(arg) => load!(vs, mem0(store, module), arg, u16, push_i64, i64),
+        Instr::I64Load32S(arg) => load!(vs, mem0(store, module), arg, i32, push_i64, i64),
+        Instr::I64Load32U(arg) => load!(vs, mem0(store, module), arg, u32, push_i64, i64),
+        Instr::I32Store(arg) => store!(vs, mem0(store, module), arg, pop_i32),
+        Instr::I64Store(arg) => store!(vs, mem0(store, module), arg, pop_i64),
+        Instr::F32Store(arg) => store!(vs, mem0(store, module), arg, pop_f32),
+        Instr::F64Store(arg) => store!(vs, mem0(store, module), arg, pop_f64),
+        Instr::I32Store8(arg) => store!(vs, mem0(store, module), arg, pop_i32, u8),
+        Instr::I32Store16(arg) => store!(vs, mem0(store, module), arg, pop_i32, u16),
+        Instr::I64Store8(arg) => store!(vs, mem0(store, module), arg, pop_i64, u8),
+        Instr::I64Store16(arg) => store!(vs, mem0(store, module), arg, pop_i64, u16),
+        Instr::I64Store32(arg) => store!(vs, mem0(store, module), arg, pop_i64, u32),
+        &Instr::TableGet(x) => {
+            let i = vs.pop_i32()? as u32;
+            let table = &store.tables[table_addr(store, module, x) as usize];
+            let r = table
+                .elem
+                .get(i as usize)
+                .ok_or(TrapKind::TableOutOfBounds)?;
+            vs.push_ref(r.clone())
+        }
+        &Instr::TableSet(x) => {
+            let r = vs.pop_ref()?;
+            let i = vs.pop_i32()? as u32;
+            let addr = table_addr(store, module, x);
+            let slot = store.tables[addr as usize]
+                .elem
+                .get_mut(i as usize)
+                .ok_or(TrapKind::TableOutOfBounds)?;
+            *slot = r;
+        }
+        &Instr::TableSize(x) => {
+            let addr = table_addr(store, module, x);
+            vs.push_i32(store.tables[addr as usize].elem.len() as i32)
+        }
+        &Instr::TableGrow(x) => {
+            let delta = vs.pop_i32()? as u32;
+            let init = vs.pop_ref()?;
+            // failing to grow isn't a trap
+            let old = table_grow(store, table_addr(store, module, x), delta, init)
+                .map_or(-1, |n| n as i32);
+            vs.push_i32(old)
+        }
+        &Instr::TableFill(x) => {
+            let n = vs.pop_i32()? as u32;
+            let r = vs.pop_ref()?;
+            let i = vs.pop_i32()? as u32;
+            let addr = table_addr(store, module, x);
+            let elem = &mut store.tables[addr as usize].elem;
+            let range = checked_range(i, n, elem.len()).ok_or(TrapKind::TableOutOfBounds)?;
+            elem[range].fill(r);
+        }
+        &Instr::TableCopy(x, y) => {
+            let n = vs.pop_i32()? as u32;
+            let s = vs.pop_i32()? as u32;
+            let d = vs.pop_i32()? as u32;
+            let (dst, src) = (table_addr(store, module, x), table_addr(store, module, y));
+            let src_range = checked_range(s, n, store.tables[src as usize].elem.len());
+            let dst_range = checked_range(d, n, store.tables[dst as usize].elem.len());
+            let (Some(src_range), Some(dst_range)) = (src_range, dst_range) else {
+                return Err(TrapKind::TableOutOfBounds);
+            };
+            // copied out first, as the ranges may overlap within one table
+            let refs = store.tables[src as usize].elem[src_range].to_vec();
+            store.tables[dst as usize].elem[dst_range].clone_from_slice(&refs);
+        }
+        &Instr::TableInit(x, y) => {
+            let n = vs.pop_i32()? as u32;
+            let s = vs.pop_i32()? as u32;
+            let d = vs.pop_i32()? as u32;
+            let (dst, src) = (table_addr(store, module, x), elem_addr(store, module, y));
+            let src_range = checked_range(s, n, store.elems[src as usize].elem.len());
+            let dst_range = checked_range(d, n, store.tables[dst as usize].elem.len());
+            let (Some(src_range), Some(dst_range)) = (src_range, dst_range) else {
+                return Err(TrapKind::TableOutOfBounds);
+            };
+            let refs = &store.elems[src as usize].elem[src_range];
+            store.tables[dst as usize].elem[dst_range].clone_from_slice(refs);
+        }
+        &Instr::ElemDrop(x) => {
+            let addr = elem_addr(store, module, x);
+            store.elems[addr as usize].elem = vec![];
+        }
+        Instr::MemorySize => vs.push_i32(mem_size(store, mem_addr(store, module)) as i32),
+        Instr::MemoryGrow => {
+            let delta = vs.pop_i32()? as u32;
+            // failing to grow isn't a trap
+            let old = mem_grow(store, mem_addr(store, module), delta).map_or(-1, |n| n as i32);
+            vs.push_i32(old)
+        }
+        // bulk memory instrs check bounds before writing anything
+        Instr::MemoryFill => {
+            let n = vs.pop_i32()? as u32;
+            let val = vs.pop_i32()?;
+            let d = vs.pop_i32()? as u32;
+            let mem = mem0(store, module);
+            let range = checked_range(d, n, mem.data.len()).ok_or(TrapKind::MemoryOutOfBounds)?;
+            mem.data[range].fill(val as u8);
+        }
+        Instr::MemoryCopy => {
+            let n = vs.pop_i32()? as u32;
+            let s = vs.pop_i32()? as u32;
+            let d = vs.pop_i32()? as u32;
+            let mem = mem0(store, module);
+            let len = mem.data.len();
+            let (Some(src), Some(_)) = (checked_range(s, n, len), checked_range(d, n, len)) else {
+                return Err(TrapKind::MemoryOutOfBounds);
+            };
+            // correct for overlapping ranges
+            mem.data.copy_within(src, d as usize);
+        }
+        &Instr::MemoryInit(x) => {
+            let n = vs.pop_i32()? as u32;
+            let s = vs.pop_i32()? as u32;
+            let d = vs.pop_i32()? as u32;
+            let (dst, src) = (mem_addr(store, module), data_addr(store, module, x));
+            let src_range = checked_range(s, n, store.datas[src as usize].data.len());
+            let dst_range = checked_range(d, n, store.mems[dst as usize].data.len());
+            let (Some(src_range), Some(dst_range)) = (src_range, dst_range) else {
+                return Err(TrapKind::MemoryOutOfBounds);
+            };
+            let bytes = &store.datas[src as usize].data[src_range];
+            store.mems[dst as usize].data[dst_range].copy_from_slice(bytes);
+        }
+        &Instr::DataDrop(x) => {
+            let addr = data_addr(store, module, x);
+            store.datas[addr as usize].data = vec![];
+        }
+        _ => exec_numeric(instr, vs)?,
+    }
+    Ok(())
+}
+
+/// Address of global `x` of module instance `module`. Imported globals are
+/// shared with the exporting instance through their address.
+fn global_addr(store: &Store, module: ModuleAddr, x: usize) -> GlobalAddr {
+    store.modules[module as usize].global_addrs[x]
+}
+
+/// Address of table `x` of module instance `module`.
+fn table_addr(store: &Store, module: ModuleAddr, x: usize) -> TableAddr {
+    store.modules[module as usize].table_addrs[x]
+}
+
+/// Address of element segment `x` of module instance `module`.
+fn elem_addr(store: &Store, module: ModuleAddr, x: usize) -> ElemAddr {
+    store.modules[module as usize].elem_addrs[x]
+}
+
+/// Address of data segment `x` of module instance `module`.
+fn data_addr(store: &Store, module: ModuleAddr, x: usize) -> DataAddr {
+    store.modules[module as usize].data_addrs[x]
+}
+
+/// The range of `n` items from `start` when it lies within `len`.
+fn checked_range(start: u32, n: u32, len: usize) -> Option<std::ops::Range<usize>> {
+    let end = start as u64 + n as u64;
+    (end <= len as u64).then_some(start as usize..end as usize)
+}
+
+/// Address of the memory of module instance `module`.
+fn mem_addr(store: &Store, module: ModuleAddr) -> MemAddr {
+    store.modules[module as usize].mem_addrs[0]
+}
+
+/// The memory of module instance `module`.
+fn mem0(store: &mut Store, module: ModuleAddr) -> &mut MemInst {
+    let addr = mem_addr(store, module);
+    &mut store.mems[addr as usize]
+}
+
+/// The bytes accessed by a memory instruction: `base` is the i32 operand,
+/// `None` when the access would go past the end of memory. The effective
+/// address is 33 bits wide, so it's computed in u64 and can't overflow.
+fn mem_range(mem: &MemInst, base: i32, arg: &MemArg, n: usize) -> Option<std::ops::Range<usize>> {
+    let ea = base as u32 as u64 + arg.offset as u64;
+    let end = ea + n as u64;
+    if end > mem.data.len() as u64 {
+        return None;
+    }
+    Some(ea as usize..end as usize)
+}
+
+fn load_bytes<const N: usize>(mem: &MemInst, base: i32, arg: &MemArg) -> Result<[u8; N], TrapKind> {
+    let range = mem_range(mem, base, arg, N).ok_or(TrapKind::MemoryOutOfBounds)?;
+    Ok(mem.data[range].try_into().unwrap())
+}
+
+fn store_bytes(mem: &mut MemInst, base: i32, arg: &MemArg, bytes: &[u8]) -> Result<(), TrapKind> {
+    let range = mem_range(mem, base, arg, bytes.len()).ok_or(TrapKind::MemoryOutOfBounds)?;
+    mem.data[range].copy_from_slice(bytes);
+    Ok(())
+}
+
+/// Execute a numeric instruction.
+pub fn exec_numeric(instr: &Instr, vs: &mut impl Operands) -> Result<(), TrapKind> {
+    match instr {
+        // i32
+        &Instr::I32Const(i) => vs.push_i32(i),
+        Instr::I32Eqz => unop!(vs, pop_i32, push_bool, |a| a == 0),
+        Instr::I32Eq => relop!(vs, pop_i32, |a, b| a == b),
+        Instr::I32Ne => relop!(vs, pop_i32, |a, b| a != b),
+        Instr::I32LtS => relop!(vs, pop_i32, |a, b| a < b),
+        Instr::I32LtU => relop!(vs, pop_i32, |a, b| (a as u32) < (b as u32)),
+        Instr::I32GtS => relop!(vs, pop_i32, |a, b| a > b),
+        Instr::I32GtU => relop!(vs, pop_i32, |a, b| (a as u32) > (b as u32)),
+        Instr::I32LeS => relop!(vs, pop_i32, |a, b| a <= b),
+        Instr::I32LeU => relop!(vs, pop_i32, |a, b| (a as u32) <= (b as u32)),
+        Instr::I32GeS => relop!(vs, pop_i32, |a, b| a >= b),
+        Instr::I32GeU => relop!(vs, pop_i32, |a, b| (a as u32) >= (b as u32)),
+        Instr::I32Clz => unop!(vs, pop_i32, push_i32, |a| a.leading_zeros() as i32),
+        Instr::I32Ctz => unop!(vs, pop_i32, push_i32, |a| a.trailing_zeros() as i32),
+        Instr::I32Popcnt => unop!(vs, pop_i32, push_i32, |a| a.count_ones() as i32),
+        Instr::I32Add => binop!(vs, pop_i32, push_i32, |a, b| a.wrapping_add(b)),
+        Instr::I32Sub => binop!(vs, pop_i32, push_i32, |a, b| a.wrapping_sub(b)),
+        Instr::I32Mul => binop!(vs, pop_i32, push_i32, |a, b| a.wrapping_mul(b)),
+        Instr::I32DivS => binop!(vs, pop_i32, push_i32, |a, b| i32_div_s(a, b)?),
+        Instr::I32DivU => binop!(vs, pop_i32, push_i32, |a, b| i32_div_u(a, b)?),
+        Instr::I32RemS => binop!(vs, pop_i32, push_i32, |a, b| i32_rem_s(a, b)?),
+        Instr::I32RemU => binop!(vs, pop_i32, push_i32, |a, b| i32_rem_u(a, b)?),
+        Instr::I32And => binop!(vs, pop_i32, push_i32, |a, b| a & b),
+        Instr::I32Or => binop!(vs, pop_i32, push_i32, |a, b| a | b),
+        Instr::I32Xor => binop!(vs, pop_i32, push_i32, |a, b| a ^ b),
+        // shift counts are taken modulo the bit width
+        Instr::I32Shl => binop!(vs, pop_i32, push_i32, |a, b| a.wrapping_shl(b as u32)),
+        Instr::I32ShrS => binop!(vs, pop_i32, push_i32, |a, b| a.wrapping_shr(b as u32)),
+        Instr::I32ShrU => binop!(vs, pop_i32, push_i32, |a, b| {
+            (a as u32).wrapping_shr(b as u32) as i32
+        }),
+        Instr::I32Rotl => binop!(vs, pop_i32, push_i32, |a, b| a.rotate_left(b as u32)),
+        Instr::I32Rotr => binop!(vs, pop_i32, push_i32, |a, b| a.rotate_right(b as u32)),
+        // i64
+        &Instr::I64Const(i) => vs.push_i64(i),
+        Instr::I64Eqz => unop!(vs, pop_i64, push_bool, |a| a == 0),
+        Instr::I64Eq => relop!(vs, pop_i64, |a, b| a == b),
+        Instr::I64Ne => relop!(vs, pop_i64, |a, b| a != b),
+        Instr::I64LtS => relop!(vs, pop_i64, |a, b| a < b),
+        Instr::I64LtU => relop!(vs, pop_i64, |a, b| (a as u64) < (b as u64)),
+        Instr::I64GtS => relop!(vs, pop_i64, |a, b| a > b),
+        Instr::I64GtU => relop!(vs, pop_i64, |a, b| (a as u64) > (b as u64)),
+        Instr::I64LeS => relop!(vs, pop_i64, |a, b| a <= b),
+        Instr::I64LeU => relop!(vs, pop_i64, |a, b| (a as u64) <= (b as u64)),
+        Instr::I64GeS => relop!(vs, pop_i64, |a, b| a >= b),
+        Instr::I64GeU => relop!(vs, pop_i64, |a, b| (a as u64) >= (b as u64)),
+        Instr::I64Clz => unop!(vs, pop_i64, push_i64, |a| a.leading_zeros() as i64),
+        Instr::I64Ctz => unop!(vs, pop_i64, push_i64, |a| a.trailing_zeros() as i64),
+        Instr::I64Popcnt => unop!(vs, pop_i64, push_i64, |a| a.count_ones() as i64),
+        Instr::I64Add => binop!(vs, pop_i64, push_i64, |a, b| a.wrapping_add(b)),
+        Instr::I64Sub => binop!(vs, pop_i64, push_i64, |a, b| a.wrapping_sub(b)),
+        Instr::I64Mul => binop!(vs, pop_i64, push_i64, |a, b| a.wrapping_mul(b)),
+        Instr::I64DivS => binop!(vs, pop_i64, push_i64, |a, b| i64_div_s(a, b)?),
+        Instr::I64DivU => binop!(vs, pop_i64, push_i64, |a, b| i64_div_u(a, b)?),
+        Instr::I64RemS => binop!(vs, pop_i64, push_i64, |a, b| i64_rem_s(a, b)?),
+        Instr::I64RemU => binop!(vs, pop_i64, push_i64, |a, b| i64_rem_u(a, b)?),
+        Instr::I64And => binop!(vs, pop_i64, push_i64, |a, b| a & b),
+        Instr::I64Or => binop!(vs, pop_i64, push_i64, |a, b| a | b),
+        Instr::I64Xor => binop!(vs, pop_i64, push_i64, |a, b| a ^ b),
+        Instr::I64Shl => binop!(vs, pop_i64, push_i64, |a, b| a.wrapping_shl(b as u32)),
+        Instr::I64ShrS => binop!(vs, pop_i64, push_i64, |a, b| a.wrapping_shr(b as u32)),
+        Instr::I64ShrU => binop!(vs, pop_i64, push_i64, |a, b| {
+            (a as u64).wrapping_shr(b as u32) as i64
+        }),
+        Instr::I64Rotl => binop!(vs, pop_i64, push_i64, |a, b| a.rotate_left(b as u32)),
+        Instr::I64Rotr => binop!(vs, pop_i64, push_i64, |a, b| a.rotate_right(b as u32)),
+        // f32
+        &Instr::F32Const(z) => vs.push_f32(z),
+        Instr::F32Eq => relop!(vs, pop_f32, |a, b| a == b),
+        Instr::F32Ne => relop!(vs, pop_f32, |a, b| a != b),
+        Instr::F32Lt => relop!(vs, pop_f32, |a, b| a < b),
+        Instr::F32Gt => relop!(vs, pop_f32, |a, b| a > b),
+        Instr::F32Le => relop!(vs, pop_f32, |a, b| a <= b),
+        Instr::F32Ge => relop!(vs, pop_f32, |a, b| a >= b),
+        Instr::F32Abs => unop!(vs, pop_f32, push_f32, |a| a.abs()),
+        Instr::F32Neg => unop!(vs, pop_f32, push_f32, |a| -a),
+        Instr::F32Ceil => unop!(vs, pop_f32, push_f32, |a| a.ceil()),
+        Instr::F32Floor => unop!(vs, pop_f32, push_f32, |a| a.floor()),
+        Instr::F32Trunc => unop!(vs, pop_f32, push_f32, |a| a.trunc()),
+        Instr::F32Nearest => unop!(vs, pop_f32, push_f32, |a| a.round_ties_even()),
+        Instr::F32Sqrt => unop!(vs, pop_f32, push_f32, |a| a.sqrt()),
+        Instr::F32Add => binop!(vs, pop_f32, push_f32, |a, b| a + b),
+        Instr::F32Sub => binop!(vs, pop_f32, push_f32, |a, b| a - b),
+        Instr::F32Mul => binop!(vs, pop_f32, push_f32, |a, b| a * b),
+        Instr::F32Div => binop!(vs, pop_f32, push_f32, |a, b| a / b),
+        Instr::F32Min => binop!(vs, pop_f32, push_f32, |a, b| f32_min(a, b)),
+        Instr::F32Max => binop!(vs, pop_f32, push_f32, |a, b| f32_max(a, b)),
+        Instr::F32Copysign => binop!(vs, pop_f32, push_f32, |a, b| a.copysign(b)),
+        // f64
+        &Instr::F64Const(z) => vs.push_f64(z),
+        Instr::F64Eq => relop!(vs, pop_f64, |a, b| a == b),
+        Instr::F64Ne => relop!(vs, pop_f64, |a, b| a != b),
+        Instr::F64Lt => relop!(vs, pop_f64, |a, b| a < b),
+        Instr::F64Gt => relop!(vs, pop_f64, |a, b| a > b),
+        Instr::F64Le => relop!(vs, pop_f64, |a, b| a <= b),
+        Instr::F64Ge => relop!(vs, pop_f64, |a, b| a >= b),
+        Instr::F64Abs => unop!(vs, pop_f64, push_f64, |a| a.abs()),
+        Instr::F64Neg => unop!(vs, pop_f64, push_f64, |a| -a),
+        Instr::F64Ceil => unop!(vs, pop_f64, push_f64, |a| a.ceil()),
+        Instr::F64Floor => unop!(vs, pop_f64, push_f64, |a| a.floor()),
+        Instr::F64Trunc => unop!(vs, pop_f64, push_f64, |a| a.trunc()),
+        Instr::F64Nearest => unop!(vs, pop_f64, push_f64, |a| a.round_ties_even()),
+        Instr::F64Sqrt => unop!(vs, pop_f64, push_f64, |a| a.sqrt()),
+        Instr::F64Add => binop!(vs, pop_f64, push_f64, |a, b| a + b),
+        Instr::F64Sub => binop!(vs, pop_f64, push_f64, |a, b| a - b),
+        Instr::F64Mul => binop!(vs, pop_f64, push_f64, |a, b| a * b),
+        Instr::F64Div => binop!(vs, pop_f64, push_f64, |a, b| a / b),
+        Instr::F64Min => binop!(vs, pop_f64, push_f64, |a, b| f64_min(a, b)),
+        Instr::F64Max => binop!(vs, pop_f64, push_f64, |a, b| f64_max(a, b)),
+        Instr::F64Copysign => binop!(vs, pop_f64, push_f64, |a, b| a.copysign(b)),
+        // conversions
+        Instr::I32WrapI64 => unop!(vs, pop_i64, push_i32, |a| a as i32),
+        Instr::I32TruncF32S => unop!(vs, pop_f32, push_i32, |a| f32_to_i32(a)?),
+        Instr::I32TruncF32U => unop!(vs, pop_f32, push_i32, |a| f32_to_u32(a)? as i32),
+        Instr::I32TruncF64S => unop!(vs, pop_f64, push_i32, |a| f64_to_i32(a)?),
+        Instr::I32TruncF64U => unop!(vs, pop_f64, push_i32, |a| f64_to_u32(a)? as i32),
+        Instr::I64ExtendI32S => unop!(vs, pop_i32, push_i64, |a| a as i64),
+        Instr::I64ExtendI32U => unop!(vs, pop_i32, push_i64, |a| a as u32 as i64),
+        Instr::I64TruncF32S => unop!(vs, pop_f32, push_i64, |a| f32_to_i64(a)?),
+        Instr::I64TruncF32U => unop!(vs, pop_f32, push_i64, |a| f32_to_u64(a)? as i64),
+        Instr::I64TruncF64S => unop!(vs, pop_f64, push_i64, |a| f64_to_i64(a)?),
+        Instr::I64TruncF64U => unop!(vs, pop_f64, push_i64, |a| f64_to_u64(a)? as i64),
+        // `as` from integers rounds to nearest, ties to even
+        Instr::F32ConvertI32S => unop!(vs, pop_i32, push_f32, |a| a as f32),
+        Instr::F32ConvertI32U => unop!(vs, pop_i32, push_f32, |a| a as u32 as f32),
+        Instr::F32ConvertI64S => unop!(vs, pop_i64, push_f32, |a| a as f32),
+        Instr::F32ConvertI64U => unop!(vs, pop_i64, push_f32, |a| a as u64 as f32),
+        Instr::F32DemoteF64 => unop!(vs, pop_f64, push_f32, |a| a as f32),
+        Instr::F64ConvertI32S => unop!(vs, pop_i32, push_f64, |a| a as f64),
+        Instr::F64ConvertI32U => unop!(vs, pop_i32, push_f64, |a| a as u32 as f64),
+        Instr::F64ConvertI64S => unop!(vs, pop_i64, push_f64, |a| a as f64),
+        Instr::F64ConvertI64U => unop!(vs, pop_i64, push_f64, |a| a as u64 as f64),
+        Instr::F64PromoteF32 => unop!(vs, pop_f32, push_f64, |a| a as f64),
+        Instr::I32ReinterpretF32 => unop!(vs, pop_f32, push_i32, |a| a.to_bits() as i32),
+        Instr::I64ReinterpretF64 => unop!(vs, pop_f64, push_i64, |a| a.to_bits() as i64),
+        Instr::F32ReinterpretI32 => unop!(vs, pop_i32, push_f32, |a| f32::from_bits(a as u32)),
+        Instr::F64ReinterpretI64 => unop!(vs, pop_i64, push_f64, |a| f64::from_bits(a as u64)),
+        Instr::I32Extend8S => unop!(vs, pop_i32, push_i32, |a| a as i8 as i32),
+        Instr::I32Extend16S => unop!(vs, pop_i32, push_i32, |a| a as i16 as i32),
+        Instr::I64Extend8S => unop!(vs, pop_i64, push_i64, |a| a as i8 as i64),
+        Instr::I64Extend16S => unop!(vs, pop_i64, push_i64, |a| a as i16 as i64),
+        Instr::I64Extend32S => unop!(vs, pop_i64, push_i64, |a| a as i32 as i64),
+        // `as` from floats saturates and maps NaN to 0
+        Instr::I32TruncSatF32S => unop!(vs, pop_f32, push_i32, |a| a as i32),
+        Instr::I32TruncSatF32U => unop!(vs, pop_f32, push_i32, |a| a as u32 as i32),
+        Instr::I32TruncSatF64S => unop!(vs, pop_f64, push_i32, |a| a as i32),
+        Instr::I32TruncSatF64U => unop!(vs, pop_f64, push_i32, |a| a as u32 as i32),
+        Instr::I64TruncSatF32S => unop!(vs, pop_f32, push_i64, |a| a as i64),
+        Instr::I64TruncSatF32U => unop!(vs, pop_f32, push_i64, |a| a as u64 as i64),
+        Instr::I64TruncSatF64S => unop!(vs, pop_f64, push_i64, |a| a as i64),
+        Instr::I64TruncSatF64U => unop!(vs, pop_f64, push_i64, |a| a as u64 as i64),
+        _ => unreachable!("not a numeric instruction: {:?}", instr),
+    }
+    Ok(())
+}
+
+/// Call a function missing from the calling engine's snapshot, `depth` frames
+/// deep not counting its own: `invoke` runs it on a fresh snapshot. A trap is
+/// reported at the call.
+pub fn call_unseen(
+    store: &mut Store,
+    depth: usize,
+    args: Vec<Val>,
+    invoke: impl FnOnce(&[FuncInst], &mut Store, Vec<Val>) -> Result<Vec<Val>, Trap>,
+) -> Result<Vec<Val>, TrapKind> {
+    if store.host_depth >= MAX_HOST_NESTING {
+        return Err(TrapKind::StackExhausted);
+    }
+    let funcs = store.funcs.clone();
+    let outer = std::mem::replace(&mut store.call_depth, depth);
+    store.host_depth += 1;
+    let results = invoke(&funcs, store, args);
+    store.host_depth -= 1;
+    store.call_depth = outer;
+    results.map_err(|trap| trap.kind)
+}
+
+/// Resolve the callee of `call_indirect` from element `i` of table `table_idx`,
+/// checking it against type `type_idx` of the calling module.
+pub fn indirect_callee(
+    store: &Store,
+    module: ModuleAddr,
+    table_idx: usize,
+    type_idx: usize,
+    i: u32,
+) -> Result<FuncAddr, TrapKind> {
+    let inst = &store.modules[module as usize];
+    let table = &store.tables[inst.table_addrs[table_idx] as usize];
+    match table.elem.get(i as usize) {
+        None => Err(TrapKind::UndefinedElement),
+        Some(Ref::Null(_)) => Err(TrapKind::UninitializedElement),
+        Some(&Ref::Func(addr)) => {
+            // function types are structural, the index they were declared at
+            // or the module declaring them doesn't matter
+            if *func_type(store, addr) == inst.types[type_idx] {
+                Ok(addr)
+            } else {
+                Err(TrapKind::IndirectCallTypeMismatch)
+            }
+        }
+        Some(Ref::Extern(_)) => Err(TrapKind::TypeMismatch),
+    }
+}
+
+macro_rules! impl_int_div {
+    ($t:ty, $u:ty, $div_s:ident, $div_u:ident, $rem_s:ident, $rem_u:ident) => {
+        fn $div_s(a: $t, b: $t) -> Result<$t, TrapKind> {
+            if b == 0 {
+                Err(TrapKind::IntegerDivideByZero)
+            } else {
+                a.checked_div(b).ok_or(TrapKind::IntegerOverflow)
+            }
+        }
+
+        fn $div_u(a: $t, b: $t) -> Result<$t, TrapKind> {
+            if b == 0 {
+                Err(TrapKind::IntegerDivideByZero)
+            } else {
+                Ok(((a as $u) / (b as $u)) as $t)
+            }
+        }
+
+        fn $rem_s(a: $t, b: $t) -> Result<$t, TrapKind> {
+            if b == 0 {
+                Err(TrapKind::IntegerDivideByZero)
+            } else {
+                // MIN rem -1 is 0, not an overflow
+                Ok(a.wrapping_rem(b))
+            }
+        }
+
+        fn $rem_u(a: $t, b: $t) -> Result<$t, TrapKind> {
+            if b == 0 {
+                Err(TrapKind::IntegerDivideByZero)
+            } else {
+                Ok(((a as $u) % (b as $u)) as $t)
+            }
+        }
+    };
+}
+
+impl_int_div!(i32, u32, i32_div_s, i32_div_u, i32_rem_s, i32_rem_u);
+impl_int_div!(i64, u64, i64_div_s, i64_div_u, i64_rem_s, i64_rem_u);
+
+macro_rules! impl_float_min_max {
+    ($t:ty, $min:ident, $max:ident) => {
+        // Unlike `f32::min`/`f32::max`, NaN wins over numbers and -0 is
+        // ordered below +0.
+        fn $min(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                // propagates an arithmetic NaN
+                a + b
+            } else if a == b {
+                <$t>::from_bits(a.to_bits() | b.to_bits())
+            } else {
+                a.min(b)
+            }
+        }
+
+        fn $max(a: $t, b: $t) -> $t {
+            if a.is_nan() || b.is_nan() {
+                a + b
+            } else if a == b {
+                <$t>::from_bits(a.to_bits() & b.to_bits())
+            } else {
+                a.max(b)
+            }
+        }
+    };
+}
+
+impl_float_min_max!(f32, f32_min, f32_max);
+impl_float_min_max!(f64, f64_min, f64_max);
+
+macro_rules! impl_float_trunc {
+    ($($name:ident, $f:ty, $i:ty, $lo:expr, $hi:expr);* $(;)?) => {
+        $(
+            // truncate towards zero, trapping unless the result lies in [lo, hi)
+            fn $name(a: $f) -> Result<$i, TrapKind> {
+                if a.is_nan() {
+                    return Err(TrapKind::InvalidConversionToInteger);
+                }
+                let t = a.trunc();
+                if ($lo..$hi).contains(&t) {
+                    Ok(t as $i)
+                } else {
+                    Err(TrapKind::IntegerOverflow)
+                }
+            }
+        )*
+    };
+}
+
+// the bounds are powers of two and exact in both float types
+impl_float_trunc!(
+    f32_to_i32, f32, i32, -2147483648.0, 2147483648.0;
+    f32_to_u32, f32, u32, 0.0, 4294967296.0;
+    f64_to_i32, f64, i32, -2147483648.0, 2147483648.0;
+    f64_to_u32, f64, u32, 0.0, 4294967296.0;
+    f32_to_i64, f32, i64, -9223372036854775808.0, 9223372036854775808.0;
+    f32_to_u64, f32, u64, 0.0, 18446744073709551616.0;
+    f64_to_i64, f64, i64, -9223372036854775808.0, 9223372036854775808.0;
+    f64_to_u64, f64, u64, 0.0, 18446744073709551616.0;
+);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{DataInst, ElemInst, GlobalInst, ModuleInst, TableInst, PAGE_SIZE};
+
+    fn eval(instrs: &[Instr]) -> Result<Vec<Val>, TrapKind> {
+        let mut vs = vec![];
+        for instr in instrs {
+            exec_numeric(instr, &mut vs)?;
+        }
+        Ok(vs)
+    }
+
+    /// Run `instrs` for a module instance whose memory holds `data`,
+    /// returning the memory afterwards too.
+    fn eval_mem(data: Vec<u8>, instrs: &[Instr]) -> (Result<Vec<Val>, TrapKind>, Vec<u8>) {
+        let mut store = mem_store(data, None);
+        let result = eval_store(&mut store, instrs);
+        (result, store.mems.pop().unwrap().data)
+    }
+
+    /// A store with a module instance whose memory holds `data`.
+    fn mem_store(data: Vec<u8>, max: Option<u32>) -> Store {
+        let mut store = Store::new();
+        let limits = Limits {
+            min: (data.len() / PAGE_SIZE) as u32,
+            max,
+        };
+        store.mems.push(MemInst {
+            mem_type: MemType { limits },
+            data,
+        });
+        store.modules.push(ModuleInst {
+            mem_addrs: vec![0],
+            ..Default::default()
+        });
+        store
+    }
+
+    /// A store with a module instance owning funcref tables and element
+    /// segments holding the given refs, all tables limited to `max`.
+    fn table_store(tables: Vec<Vec<Ref>>, elems: Vec<Vec<Ref>>, max: Option<u32>) -> Store {
+        let mut store = Store::new();
+        let mut inst = ModuleInst::default();
+        for elem in tables {
+            let limits = Limits {
+                min: elem.len() as u32,
+                max,
+            };
+            inst.table_addrs.push(store.tables.len() as u32);
+            store.tables.push(TableInst {
+                table_type: TableType {
+                    limits,
+                    elem_type: RefType::FuncRef,
+                },
+                elem,
+            });
+        }
+        for elem in elems {
+            inst.elem_addrs.push(store.elems.len() as u32);
+            store.elems.push(ElemInst {
+                elem_type: RefType::FuncRef,
+                elem,
+            });
+        }
+        store.modules.push(inst);
+        store
+    }
+
+    fn null() -> Ref {
+        Ref::Null(RefType::FuncRef)
+    }
+
+    fn eval_store(store: &mut Store, instrs: &[Instr]) -> Result<Vec<Val>, TrapKind> {
+        let mut vs = vec![];
+        for instr in instrs {
+            exec_plain(instr, store, 0, &mut vs)?;
+        }
+        Ok(vs)
+    }
+
+    fn arg(offset: u32) -> MemArg {
+        MemArg { offset, align: 0 }
+    }
+
+    fn i32_bin(a: i32, b: i32, op: Instr) -> Result<Vec<Val>, TrapKind> {
+        eval(&[Instr::I32Const(a), Instr::I32Const(b), op])
+    }
+
+    fn i64_bin(a: i64, b: i64, op: Instr) -> Result<Vec<Val>, TrapKind> {
+        eval(&[Instr::I64Const(a), Instr::I64Const(b), op])
+    }
+
+    #[test]
+    fn test_i32_arith_wraps() {
+        assert_eq!(
+            Ok(vec![Val::I32(i32::MIN)]),
+            i32_bin(i32::MAX, 1, Instr::I32Add)
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(i32::MAX)]),
+            i32_bin(i32::MIN, 1, Instr::I32Sub)
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(0)]),
+            i32_bin(0x10000, 0x10000, Instr::I32Mul)
+        );
+        assert_eq!(Ok(vec![Val::I32(-1)]), i32_bin(3, 4, Instr::I32Sub));
+    }
+
+    #[test]
+    fn test_i32_div_rem() {
+        assert_eq!(Ok(vec![Val::I32(-2)]), i32_bin(-7, 3, Instr::I32DivS));
+        assert_eq!(Ok(vec![Val::I32(-1)]), i32_bin(-7, 3, Instr::I32RemS));
+        assert_eq!(
+            Ok(vec![Val::I32(0x55555553)]),
+            i32_bin(-7, 3, Instr::I32DivU)
+        );
+        assert_eq!(Ok(vec![Val::I32(0)]), i32_bin(i32::MIN, -1, Instr::I32RemS));
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            i32_bin(i32::MIN, -1, Instr::I32DivS)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerDivideByZero),
+            i32_bin(1, 0, Instr::I32DivS)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerDivideByZero),
+            i32_bin(1, 0, Instr::I32DivU)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerDivideByZero),
+            i32_bin(1, 0, Instr::I32RemS)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerDivideByZero),
+            i32_bin(1, 0, Instr::I32RemU)
+        );
+    }
+
+    #[test]
+    fn test_i32_bits() {
+        assert_eq!(Ok(vec![Val::I32(2)]), i32_bin(1, 33, Instr::I32Shl));
+        assert_eq!(Ok(vec![Val::I32(-1)]), i32_bin(-2, 1, Instr::I32ShrS));
+        assert_eq!(Ok(vec![Val::I32(i32::MAX)]), i32_bin(-1, 1, Instr::I32ShrU));
+        assert_eq!(Ok(vec![Val::I32(1)]), i32_bin(i32::MIN, 1, Instr::I32Rotl));
+        assert_eq!(Ok(vec![Val::I32(i32::MIN)]), i32_bin(1, 33, Instr::I32Rotr));
+        assert_eq!(
+            Ok(vec![Val::I32(32)]),
+            eval(&[Instr::I32Const(0), Instr::I32Clz])
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(31)]),
+            eval(&[Instr::I32Const(i32::MIN), Instr::I32Ctz])
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(32)]),
+            eval(&[Instr::I32Const(-1), Instr::I32Popcnt])
+        );
+    }
+
+    #[test]
+    fn test_i32_compare() {
+        assert_eq!(Ok(vec![Val::I32(1)]), i32_bin(-1, 1, Instr::I32LtS));
+        assert_eq!(Ok(vec![Val::I32(0)]), i32_bin(-1, 1, Instr::I32LtU));
+        assert_eq!(Ok(vec![Val::I32(1)]), i32_bin(-1, 1, Instr::I32GeU));
+        assert_eq!(Ok(vec![Val::I32(1)]), i32_bin(5, 5, Instr::I32LeS));
+        assert_eq!(Ok(vec![Val::I32(0)]), i32_bin(5, 5, Instr::I32Ne));
+        assert_eq!(
+            Ok(vec![Val::I32(1)]),
+            eval(&[Instr::I32Const(0), Instr::I32Eqz])
+        );
+    }
+
+    #[test]
+    fn test_i64_ops() {
+        assert_eq!(
+            Ok(vec![Val::I64(i64::MIN)]),
+            i64_bin(i64::MAX, 1, Instr::I64Add)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            i64_bin(i64::MIN, -1, Instr::I64DivS)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerDivideByZero),
+            i64_bin(1, 0, Instr::I64RemU)
+        );
+        assert_eq!(Ok(vec![Val::I64(0)]), i64_bin(i64::MIN, -1, Instr::I64RemS));
+        assert_eq!(Ok(vec![Val::I64(2)]), i64_bin(1, 65, Instr::I64Shl));
+        assert_eq!(Ok(vec![Val::I64(i64::MAX)]), i64_bin(-1, 1, Instr::I64ShrU));
+        assert_eq!(Ok(vec![Val::I32(1)]), i64_bin(1, -1, Instr::I64LtU));
+        assert_eq!(
+            Ok(vec![Val::I64(64)]),
+            eval(&[Instr::I64Const(0), Instr::I64Ctz])
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(0)]),
+            eval(&[Instr::I64Const(1), Instr::I64Eqz])
+        );
+    }
+
+    fn f32_bin(a: f32, b: f32, op: Instr) -> Val {
+        eval(&[Instr::F32Const(a), Instr::F32Const(b), op]).unwrap()[0].clone()
+    }
+
+    fn f64_bin(a: f64, b: f64, op: Instr) -> Val {
+        eval(&[Instr::F64Const(a), Instr::F64Const(b), op]).unwrap()[0].clone()
+    }
+
+    fn f32_bits(v: Val) -> u32 {
+        match v {
+            Val::F32(z) => z.to_bits(),
+            _ => panic!("not an f32: {:?}", v),
+        }
+    }
+
+    fn f64_bits(v: Val) -> u64 {
+        match v {
+            Val::F64(z) => z.to_bits(),
+            _ => panic!("not an f64: {:?}", v),
+        }
+    }
+
+    fn is_arithmetic_nan_f32(v: Val) -> bool {
+        let bits = f32_bits(v);
+        f32::from_bits(bits).is_nan() && bits & 0x0040_0000 != 0
+    }
+
+    #[test]
+    fn test_float_arith() {
+        assert_eq!(Val::F32(3.5), f32_bin(1.25, 2.25, Instr::F32Add));
+        assert_eq!(Val::F64(-1.0), f64_bin(1.25, 2.25, Instr::F64Sub));
+        assert_eq!(Val::F64(f64::INFINITY), f64_bin(1.0, 0.0, Instr::F64Div));
+        assert!(is_arithmetic_nan_f32(f32_bin(0.0, 0.0, Instr::F32Div)));
+        assert!(is_arithmetic_nan_f32(f32_bin(
+            f32::INFINITY,
+            f32::NEG_INFINITY,
+            Instr::F32Add
+        )));
+    }
+
+    #[test]
+    fn test_float_min_max() {
+        // f32.wast: (f32.min (f32.const -0x0p+0) (f32.const 0x0p+0)) => -0x0p+0
+        assert_eq!(0x8000_0000, f32_bits(f32_bin(-0.0, 0.0, Instr::F32Min)));
+        assert_eq!(0x8000_0000, f32_bits(f32_bin(0.0, -0.0, Instr::F32Min)));
+        assert_eq!(0, f32_bits(f32_bin(-0.0, 0.0, Instr::F32Max)));
+        assert_eq!(0, f64_bits(f64_bin(0.0, -0.0, Instr::F64Max)));
+        assert!(is_arithmetic_nan_f32(f32_bin(f32::NAN, 1.0, Instr::F32Min)));
+        assert!(is_arithmetic_nan_f32(f32_bin(1.0, f32::NAN, Instr::F32Max)));
+        // a signalling NaN comes out quieted
+        let snan = f32::from_bits(0x7fa0_0000);
+        assert!(is_arithmetic_nan_f32(f32_bin(snan, 1.0, Instr::F32Max)));
+        assert_eq!(Val::F64(-2.0), f64_bin(-2.0, 3.0, Instr::F64Min));
+    }
+
+    #[test]
+    fn test_float_sign_ops_keep_nan_payload() {
+        let nan = f32::from_bits(0x7fa0_0001);
+        let neg = eval(&[Instr::F32Const(nan), Instr::F32Neg]).unwrap();
+        assert_eq!(0xffa0_0001, f32_bits(neg[0].clone()));
+        let abs = eval(&[Instr::F32Const(f32::from_bits(0xffa0_0001)), Instr::F32Abs]).unwrap();
+        assert_eq!(0x7fa0_0001, f32_bits(abs[0].clone()));
+        assert_eq!(0x7fa0_0001, f32_bits(f32_bin(nan, 1.0, Instr::F32Copysign)));
+        assert_eq!(Val::F64(-1.5), f64_bin(1.5, -0.0, Instr::F64Copysign));
+    }
+
+    #[test]
+    fn test_float_nearest() {
+        let nearest = |z: f64| eval(&[Instr::F64Const(z), Instr::F64Nearest]).unwrap()[0].clone();
+        assert_eq!(Val::F64(2.0), nearest(2.5));
+        assert_eq!(Val::F64(4.0), nearest(3.5));
+        assert_eq!(Val::F64(-4.0), nearest(-3.5));
+        assert_eq!(0x8000_0000_0000_0000, f64_bits(nearest(-0.5)));
+        let r = eval(&[Instr::F32Const(-0.5), Instr::F32Ceil]).unwrap();
+        assert_eq!(0x8000_0000, f32_bits(r[0].clone()));
+    }
+
+    #[test]
+    fn test_float_compare() {
+        assert_eq!(Val::I32(0), f32_bin(f32::NAN, f32::NAN, Instr::F32Eq));
+        assert_eq!(Val::I32(1), f32_bin(f32::NAN, f32::NAN, Instr::F32Ne));
+        assert_eq!(Val::I32(1), f64_bin(-0.0, 0.0, Instr::F64Eq));
+        assert_eq!(Val::I32(0), f64_bin(f64::NAN, 0.0, Instr::F64Ge));
+        assert_eq!(Val::I32(1), f64_bin(-1.0, 0.0, Instr::F64Lt));
+    }
+
+    #[test]
+    fn test_trunc() {
+        let trunc = |z: Instr, op: Instr| eval(&[z, op]);
+        // conversions.wast boundaries
+        assert_eq!(
+            Ok(vec![Val::I32(i32::MIN)]),
+            trunc(Instr::F32Const(-2147483648.0), Instr::I32TruncF32S)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            trunc(Instr::F32Const(2147483648.0), Instr::I32TruncF32S)
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(i32::MAX)]),
+            trunc(Instr::F64Const(2147483647.9), Instr::I32TruncF64S)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            trunc(Instr::F64Const(-2147483649.0), Instr::I32TruncF64S)
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(0)]),
+            trunc(Instr::F32Const(-0.9), Instr::I32TruncF32U)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            trunc(Instr::F32Const(-1.0), Instr::I32TruncF32U)
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(-1)]),
+            trunc(Instr::F64Const(4294967295.0), Instr::I32TruncF64U)
+        );
+        assert_eq!(
+            Err(TrapKind::InvalidConversionToInteger),
+            trunc(Instr::F32Const(f32::NAN), Instr::I64TruncF32S)
+        );
+        assert_eq!(
+            Err(TrapKind::IntegerOverflow),
+            trunc(Instr::F64Const(9223372036854775808.0), Instr::I64TruncF64S)
+        );
+        assert_eq!(
+            Ok(vec![Val::I64(-2048)]),
+            trunc(Instr::F64Const(18446744073709549568.0), Instr::I64TruncF64U)
+        );
+    }
+
+    #[test]
+    fn test_trunc_sat() {
+        let sat = |z: Instr, op: Instr| eval(&[z, op]).unwrap()[0].clone();
+        assert_eq!(
+            Val::I32(i32::MAX),
+            sat(Instr::F32Const(1e10), Instr::I32TruncSatF32S)
+        );
+        assert_eq!(
+            Val::I32(0),
+            sat(Instr::F32Const(f32::NAN), Instr::I32TruncSatF32S)
+        );
+        assert_eq!(
+            Val::I32(0),
+            sat(Instr::F64Const(-1.0), Instr::I32TruncSatF64U)
+        );
+        assert_eq!(
+            Val::I32(-1),
+            sat(Instr::F64Const(1e20), Instr::I32TruncSatF64U)
+        );
+        assert_eq!(
+            Val::I64(i64::MIN),
+            sat(Instr::F64Const(-1e30), Instr::I64TruncSatF64S)
+        );
+    }
+
+    #[test]
+    fn test_convert() {
+        let conv = |z: Instr, op: Instr| eval(&[z, op]).unwrap()[0].clone();
+        // round to nearest even: 2^24 + 1 is not representable in f32
+        assert_eq!(
+            Val::F32(16777216.0),
+            conv(Instr::I32Const(16777217), Instr::F32ConvertI32S)
+        );
+        assert_eq!(
+            Val::F32(4294967296.0),
+            conv(Instr::I32Const(-1), Instr::F32ConvertI32U)
+        );
+        assert_eq!(
+            Val::F64(18446744073709551616.0),
+            conv(Instr::I64Const(-1), Instr::F64ConvertI64U)
+        );
+        assert_eq!(
+            Val::F32(f32::INFINITY),
+            conv(Instr::F64Const(1e300), Instr::F32DemoteF64)
+        );
+        assert_eq!(
+            Val::I64(0xffff_ffff),
+            conv(Instr::I32Const(-1), Instr::I64ExtendI32U)
+        );
+        assert_eq!(
+            Val::I64(-1),
+            conv(Instr::I32Const(-1), Instr::I64ExtendI32S)
+        );
+        assert_eq!(
+            Val::I32(-1),
+            conv(Instr::I64Const(0x1_ffff_ffff), Instr::I32WrapI64)
+        );
+        assert_eq!(
+            Val::I32(-128),
+            conv(Instr::I32Const(0x80), Instr::I32Extend8S)
+        );
+        assert_eq!(
+            Val::I64(0x7fff),
+            conv(Instr::I64Const(0x1_7fff), Instr::I64Extend16S)
+        );
+    }
+
+    #[test]
+    fn test_reinterpret() {
+        let conv = |z: Instr, op: Instr| eval(&[z, op]).unwrap()[0].clone();
+        assert_eq!(
+            Val::I32(i32::MIN),
+            conv(Instr::F32Const(-0.0), Instr::I32ReinterpretF32)
+        );
+        let snan = conv(Instr::I32Const(0x7fa0_0000), Instr::F32ReinterpretI32);
+        assert_eq!(0x7fa0_0000, f32_bits(snan));
+        assert_eq!(
+            Val::I64(0x3ff0_0000_0000_0000),
+            conv(Instr::F64Const(1.0), Instr::I64ReinterpretF64)
+        );
+    }
+
+    #[test]
+    fn test_type_mismatch() {
+        assert_eq!(
+            Err(TrapKind::TypeMismatch),
+            eval(&[Instr::I64Const(1), Instr::I32Const(1), Instr::I32Add])
+        );
+    }
+
+    #[test]
+    fn test_load_little_endian() {
+        let data = vec![0x01, 0x02, 0x03, 0x04, 0x80, 0xff, 0xff, 0xff];
+        let (result, _) = eval_mem(
+            data,
+            &[
+                Instr::I32Const(0),
+                Instr::I32Load(arg(0)),
+                Instr::I32Const(0),
+                Instr::I64Load(arg(0)),
+                Instr::I32Const(2),
+                Instr::I32Load16U(arg(1)),
+            ],
+        );
+        assert_eq!(
+            Ok(vec![
+                Val::I32(0x04030201),
+                Val::I64(0xffffff80_04030201u64 as i64),
+                Val::I32(0x8004),
+            ]),
+            result
+        );
+    }
+
+    #[test]
+    fn test_load_extend() {
+        let data = vec![0x80, 0xff, 0xff, 0xff];
+        let load = |instr| eval_mem(data.clone(), &[Instr::I32Const(0), instr]).0;
+        assert_eq!(Ok(vec![Val::I32(-128)]), load(Instr::I32Load8S(arg(0))));
+        assert_eq!(Ok(vec![Val::I32(0x80)]), load(Instr::I32Load8U(arg(0))));
+        assert_eq!(Ok(vec![Val::I32(-128)]), load(Instr::I32Load16S(arg(0))));
+        assert_eq!(Ok(vec![Val::I32(0xff80)]), load(Instr::I32Load16U(arg(0))));
+        assert_eq!(Ok(vec![Val::I64(-128)]), load(Instr::I64Load8S(arg(0))));
+        assert_eq!(Ok(vec![Val::I64(0x80)]), load(Instr::I64Load8U(arg(0))));
+        assert_eq!(Ok(vec![Val::I64(-128)]), load(Instr::I64Load16S(arg(0))));
+        assert_eq!(Ok(vec![Val::I64(0xff80)]), load(Instr::I64Load16U(arg(0))));
+        assert_eq!(Ok(vec![Val::I64(-128)]), load(Instr::I64Load32S(arg(0))));
+        assert_eq!(
+            Ok(vec![Val::I64(0xffffff80)]),
+            load(Instr::I64Load32U(arg(0)))
+        );
+    }
+
+    #[test]
+    fn test_store_wraps() {
+        let (result, data) = eval_mem(
+            vec![0; 8],
+            &[
+                Instr::I32Const(0),
+                Instr::I32Const(0x1234),
+                Instr::I32Store8(arg(0)),
+                Instr::I32Const(1),
+                Instr::I64Const(0x1_2345_6789),
+                Instr::I64Store32(arg(2)),
+            ],
+        );
+        assert_eq!(Ok(vec![]), result);
+        assert_eq!(vec![0x34, 0, 0, 0x89, 0x67, 0x45, 0x23, 0], data);
+    }
+
+    #[test]
+    fn test_float_store_load() {
+        let (result, _) = eval_mem(
+            vec![0; 16],
+            &[
+                Instr::I32Const(8),
+                Instr::F64Const(-1.5),
+                Instr::F64Store(arg(0)),
+                Instr::I32Const(0),
+                Instr::F32Const(f32::INFINITY),
+                Instr::F32Store(arg(4)),
+                Instr::I32Const(0),
+                Instr::F64Load(arg(8)),
+                Instr::I32Const(4),
+                Instr::F32Load(arg(0)),
+                Instr::I32Const(4),
+                Instr::I32Load(arg(0)),
+            ],
+        );
+        assert_eq!(
+            Ok(vec![
+                Val::F64(-1.5),
+                Val::F32(f32::INFINITY),
+                Val::I32(0x7f800000)
+            ]),
+            result
+        );
+    }
+
+    #[test]
+    fn test_memory_out_of_bounds() {
+        let load = |base, offset| {
+            eval_mem(
+                vec![0; 16],
+                &[Instr::I32Const(base), Instr::I32Load(arg(offset))],
+            )
+            .0
+        };
+        assert_eq!(Ok(vec![Val::I32(0)]), load(12, 0));
+        assert_eq!(Ok(vec![Val::I32(0)]), load(0, 12));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), load(13, 0));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), load(4, 9));
+        // the effective address doesn't wrap around
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), load(-1, 1));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), load(1, u32::MAX));
+    }
+
+    #[test]
+    fn test_store_out_of_bounds_writes_nothing() {
+        let (result, data) = eval_mem(
+            vec![0; 4],
+            &[
+                Instr::I32Const(2),
+                Instr::I32Const(-1),
+                Instr::I32Store(arg(0)),
+            ],
+        );
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), result);
+        assert_eq!(vec![0; 4], data);
+    }
+
+    #[test]
+    fn test_memory_grow() {
+        let mut store = mem_store(vec![0; PAGE_SIZE], Some(3));
+        let grow = |delta| [Instr::I32Const(delta), Instr::MemoryGrow];
+        assert_eq!(Ok(vec![Val::I32(1)]), eval_store(&mut store, &grow(0)));
+        assert_eq!(Ok(vec![Val::I32(1)]), eval_store(&mut store, &grow(2)));
+        assert_eq!(
+            Ok(vec![Val::I32(3)]),
+            eval_store(&mut store, &[Instr::MemorySize])
+        );
+        // new pages are zeroed and accessible
+        assert_eq!(
+            Ok(vec![Val::I32(0)]),
+            eval_store(
+                &mut store,
+                &[
+                    Instr::I32Const(3 * PAGE_SIZE as i32 - 4),
+                    Instr::I32Load(arg(0))
+                ]
+            )
+        );
+        // past the max, the size is unchanged
+        assert_eq!(Ok(vec![Val::I32(-1)]), eval_store(&mut store, &grow(1)));
+        assert_eq!(Ok(vec![Val::I32(-1)]), eval_store(&mut store, &grow(-1)));
+        assert_eq!(3, mem_size(&store, 0));
+        assert_eq!(3, store.mems[0].mem_type.limits.min);
+    }
+
+    #[test]
+    fn test_memory_grow_embedder_cap() {
+        let mut store = mem_store(vec![], None);
+        store.max_mem_pages = Some(2);
+        assert_eq!(Ok(0), mem_grow(&mut store, 0, 2));
+        assert!(mem_grow(&mut store, 0, 1).is_err());
+        assert_eq!(2 * PAGE_SIZE, store.mems[0].data.len());
+        // the spec's 4 GiB bound applies without a max
+        store.max_mem_pages = None;
+        assert!(mem_grow(&mut store, 0, 65535).is_err());
+    }
+
+    #[test]
+    fn test_table_get_set() {
+        let mut store = table_store(vec![vec![null(), Ref::Func(3)]], vec![], None);
+        let result = eval_store(
+            &mut store,
+            &[
+                Instr::I32Const(0),
+                Instr::I32Const(1),
+                Instr::TableGet(0),
+                Instr::TableSet(0),
+                Instr::I32Const(0),
+                Instr::TableGet(0),
+                Instr::TableSize(0),
+            ],
+        );
+        assert_eq!(Ok(vec![Val::Ref(Ref::Func(3)), Val::I32(2)]), result);
+        let get = [Instr::I32Const(2), Instr::TableGet(0)];
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &get)
+        );
+        let set = [
+            Instr::I32Const(-1),
+            Instr::RefNull(RefType::FuncRef),
+            Instr::TableSet(0),
+        ];
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &set)
+        );
+    }
+
+    #[test]
+    fn test_table_grow() {
+        let mut store = table_store(vec![vec![null()]], vec![], Some(3));
+        let grow = |n| {
+            [
+                Instr::I32Const(5),
+                Instr::I32Const(0),
+                Instr::TableGet(0),
+                Instr::I32Const(n),
+                Instr::TableGrow(0),
+            ]
+        };
+        store.tables[0].elem[0] = Ref::Func(9);
+        assert_eq!(
+            Ok(vec![Val::I32(5), Val::I32(1)]),
+            eval_store(&mut store, &grow(2))
+        );
+        assert_eq!(
+            Ok(vec![Val::I32(5), Val::I32(-1)]),
+            eval_store(&mut store, &grow(1))
+        );
+        assert_eq!(vec![Ref::Func(9); 3], store.tables[0].elem);
+        assert_eq!(3, store.tables[0].table_type.limits.min);
+        assert_eq!(Ok(3), table_grow(&mut store, 0, 0, null()));
+        assert!(table_grow(&mut store, 0, u32::MAX, null()).is_err());
+    }
+
+    #[test]
+    fn test_table_fill() {
+        let mut store = table_store(vec![vec![null(); 4]], vec![], None);
+        store.tables[0].elem[0] = Ref::Func(5);
+        let fill = |i, n| {
+            [
+                Instr::I32Const(i),
+                Instr::I32Const(0),
+                Instr::TableGet(0),
+                Instr::I32Const(n),
+                Instr::TableFill(0),
+            ]
+        };
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &fill(4, 0)));
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &fill(2, 3))
+        );
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &fill(1, 2)));
+        assert_eq!(
+            vec![Ref::Func(5), Ref::Func(5), Ref::Func(5), null()],
+            store.tables[0].elem
+        );
+    }
+
+    #[test]
+    fn test_table_copy() {
+        let refs = (0..5).map(Ref::Func).collect::<Vec<_>>();
+        let mut store = table_store(vec![refs, vec![null(); 2]], vec![], None);
+        let copy = |x, y, d, s, n| {
+            [
+                Instr::I32Const(d),
+                Instr::I32Const(s),
+                Instr::I32Const(n),
+                Instr::TableCopy(x, y),
+            ]
+        };
+        // overlapping, forwards
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &copy(0, 0, 1, 0, 3)));
+        let funcs = |addrs: &[u32]| addrs.iter().map(|&a| Ref::Func(a)).collect::<Vec<_>>();
+        assert_eq!(funcs(&[0, 0, 1, 2, 4]), store.tables[0].elem);
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &copy(1, 0, 0, 3, 2)));
+        assert_eq!(funcs(&[2, 4]), store.tables[1].elem);
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &copy(1, 0, 1, 0, 2))
+        );
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &copy(0, 0, 0, 4, 2))
+        );
+    }
+
+    #[test]
+    fn test_table_init_elem_drop() {
+        let mut store = table_store(
+            vec![vec![null(); 3]],
+            vec![vec![Ref::Func(7), Ref::Func(8)]],
+            None,
+        );
+        let init = |d, s, n| {
+            [
+                Instr::I32Const(d),
+                Instr::I32Const(s),
+                Instr::I32Const(n),
+                Instr::TableInit(0, 0),
+            ]
+        };
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &init(1, 0, 2)));
+        assert_eq!(
+            vec![null(), Ref::Func(7), Ref::Func(8)],
+            store.tables[0].elem
+        );
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &init(2, 0, 2))
+        );
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &[Instr::ElemDrop(0)]));
+        // a dropped segment is empty
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &init(0, 0, 0)));
+        assert_eq!(
+            Err(TrapKind::TableOutOfBounds),
+            eval_store(&mut store, &init(0, 0, 1))
+        );
+    }
+
+    #[test]
+    fn test_global_shared_between_instances() {
+        let mut store = Store::new();
+        let global = |mutability, value| GlobalInst {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability,
+            },
+            value,
+        };
+        store.globals.push(global(Mutability::Var, Val::I32(1)));
+        store.globals.push(global(Mutability::Const, Val::I32(2)));
+        // instance 1 imports the mutable global of instance 0 as its global 1
+        store.modules.push(ModuleInst {
+            global_addrs: vec![0],
+            ..Default::default()
+        });
+        store.modules.push(ModuleInst {
+            global_addrs: vec![1, 0],
+            ..Default::default()
+        });
+        let mut vs = vec![];
+        let mut exec = |module, instr| exec_plain(&instr, &mut store, module, &mut vs);
+        exec(0, Instr::I32Const(5)).unwrap();
+        exec(0, Instr::GlobalSet(0)).unwrap();
+        exec(1, Instr::GlobalGet(1)).unwrap();
+        exec(1, Instr::GlobalGet(0)).unwrap();
+        assert_eq!(vec![Val::I32(5), Val::I32(2)], vs);
+        // only reachable without validation
+        let mut vs = vec![Val::I32(0)];
+        assert_eq!(
+            Err(TrapKind::TypeMismatch),
+            exec_plain(&Instr::GlobalSet(0), &mut store, 1, &mut vs)
+        );
+    }
+
+    #[test]
+    fn test_memory_fill() {
+        let fill = |d, val, n| {
+            [
+                Instr::I32Const(d),
+                Instr::I32Const(val),
+                Instr::I32Const(n),
+                Instr::MemoryFill,
+            ]
+        };
+        let (result, data) = eval_mem(vec![0; 6], &fill(1, 0x1ff, 3));
+        assert_eq!(Ok(vec![]), result);
+        assert_eq!(vec![0, 0xff, 0xff, 0xff, 0, 0], data);
+        assert_eq!(
+            (Ok(vec![]), vec![0; 6]),
+            eval_mem(vec![0; 6], &fill(6, 1, 0))
+        );
+        // nothing is written on a trap
+        assert_eq!(
+            (Err(TrapKind::MemoryOutOfBounds), vec![0; 6]),
+            eval_mem(vec![0; 6], &fill(4, 1, 3))
+        );
+        assert_eq!(
+            (Err(TrapKind::MemoryOutOfBounds), vec![0; 6]),
+            eval_mem(vec![0; 6], &fill(7, 1, 0))
+        );
+    }
+
+    #[test]
+    fn test_memory_copy_overlap() {
+        let copy = |d, s, n| {
+            [
+                Instr::I32Const(d),
+                Instr::I32Const(s),
+                Instr::I32Const(n),
+                Instr::MemoryCopy,
+            ]
+        };
+        let data = vec![1, 2, 3, 4, 5, 6];
+        assert_eq!(
+            (Ok(vec![]), vec![1, 1, 2, 3, 4, 6]),
+            eval_mem(data.clone(), &copy(1, 0, 4))
+        );
+        assert_eq!(
+            (Ok(vec![]), vec![2, 3, 4, 5, 5, 6]),
+            eval_mem(data.clone(), &copy(0, 1, 4))
+        );
+        assert_eq!(
+            (Err(TrapKind::MemoryOutOfBounds), data.clone()),
+            eval_mem(data.clone(), &copy(0, 3, 4))
+        );
+        assert_eq!(
+            (Err(TrapKind::MemoryOutOfBounds), data.clone()),
+            eval_mem(data.clone(), &copy(3, 0, 4))
+        );
+        assert_eq!(
+            (Err(TrapKind::MemoryOutOfBounds), data.clone()),
+            eval_mem(data, &copy(-1, 0, 2))
+        );
+    }
+
+    #[test]
+    fn test_memory_init_data_drop() {
+        let mut store = mem_store(vec![0; 4], None);
+        store.datas.push(DataInst {
+            data: vec![7, 8, 9],
+        });
+        store.modules[0].data_addrs.push(0);
+        let init = |d, s, n| {
+            [
+                Instr::I32Const(d),
+                Instr::I32Const(s),
+                Instr::I32Const(n),
+                Instr::MemoryInit(0),
+            ]
+        };
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &init(2, 1, 2)));
+        assert_eq!(vec![0, 0, 8, 9], store.mems[0].data);
+        assert_eq!(
+            Err(TrapKind::MemoryOutOfBounds),
+            eval_store(&mut store, &init(0, 2, 2))
+        );
+        assert_eq!(
+            Err(TrapKind::MemoryOutOfBounds),
+            eval_store(&mut store, &init(3, 0, 2))
+        );
+        assert_eq!(vec![0, 0, 8, 9], store.mems[0].data);
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &[Instr::DataDrop(0)]));
+        // a dropped segment is empty
+        assert_eq!(Ok(vec![]), eval_store(&mut store, &init(4, 0, 0)));
+        assert_eq!(
+            Err(TrapKind::MemoryOutOfBounds),
+            eval_store(&mut store, &init(0, 0, 1))
+        );
+    }
+}
@@ -0,0 +1,768 @@
+//! Flat bytecode: function bodies lowered from nested `Instr` trees into a
+//! single op array with resolved jump targets and stack adjustments, run by
+//! a dispatch loop without labels on the stack.
+
+use std::rc::Rc;
+
+use crate::exec::{call_unseen, exec_plain, indirect_callee, Operands, TaggedOperands};
+use crate::module::{func_type, host_call, FuncAddr, FuncInst, ModuleAddr, Store};
+use crate::trap::{Trap, TrapKind};
+use crate::types::*;
+
+/// A branch: keep the top `keep` operands, drop the `drop` operands below
+/// them and continue at op `target`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Jump {
+    pub target: usize,
+    pub drop: usize,
+    pub keep: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Op {
+    Unreachable,
+    LocalGet(usize),
+    LocalSet(usize),
+    LocalTee(usize),
+    Br(Jump),
+    BrIf(Jump),
+    /// Jumps indexed by the operand, the default jump last
+    BrTable(Box<[Jump]>),
+    /// Pop a condition and jump to `target` if it is zero
+    BrUnless(usize),
+    Return,
+    Call(FuncAddr),
+    /// Table index, type index
+    CallIndirect(usize, usize),
+    /// Any other instruction, run by `exec_plain`
+    Plain(Instr),
+}
+
+/// A lowered function body.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Compiled {
+    pub ops: Vec<Op>,
+    /// Position of the instruction each op was lowered from, in the form of
+    /// `Trap::pos`
+    pub pos: Vec<Vec<usize>>,
+    pub arity: usize,
+    pub locals: Vec<ValType>,
+}
+
+/// A block being lowered.
+struct Ctrl {
+    /// Branch target of a loop, `None` for blocks whose end is not known yet
+    start: Option<usize>,
+    /// Operand height below the block's params
+    height: usize,
+    /// Number of operands taken by a branch to the block
+    label_arity: usize,
+    results: usize,
+    /// Ops branching to the end of the block, with the index into a `BrTable`
+    fixups: Vec<(usize, usize)>,
+    /// Set after an unconditional branch, the rest of the block is dead code
+    unreachable: bool,
+}
+
+struct Lower<'s> {
+    store: &'s Store,
+    funcs: &'s [FuncInst],
+    module: ModuleAddr,
+    ops: Vec<Op>,
+    pos: Vec<Vec<usize>>,
+    ctrls: Vec<Ctrl>,
+    /// Current operand height, relative to the frame
+    height: usize,
+    path: Vec<usize>,
+}
+
+/// Lower local function `addr` of `funcs`, a snapshot of the store's. Fails
+/// on code that would not validate, where operand heights can't be tracked.
+pub fn compile(store: &Store, funcs: &[FuncInst], addr: FuncAddr) -> Result<Compiled, TrapKind> {
+    let FuncInst::Local {
+        func_type,
+        module,
+        code,
+    } = &funcs[addr as usize]
+    else {
+        unreachable!("not a local function: {}", addr);
+    };
+    let arity = func_type.results.len();
+    let mut lower = Lower {
+        store,
+        funcs,
+        module: *module,
+        ops: vec![],
+        pos: vec![],
+        // the body is a block, `br` to it returns
+        ctrls: vec![Ctrl {
+            start: None,
+            height: 0,
+            label_arity: arity,
+            results: arity,
+            fixups: vec![],
+            unreachable: false,
+        }],
+        height: 0,
+        path: vec![],
+    };
+    lower.block(&code.body.instrs)?;
+    lower.end_block();
+    let mut locals = func_type.params.clone();
+    locals.extend(code.locals.iter().cloned());
+    Ok(Compiled {
+        ops: lower.ops,
+        pos: lower.pos,
+        arity,
+        locals,
+    })
+}
+
+impl Lower<'_> {
+    fn emit(&mut self, op: Op) -> usize {
+        self.ops.push(op);
+        self.pos.push(self.path.clone());
+        self.ops.len() - 1
+    }
+
+    fn pop(&mut self, n: usize) -> Result<(), TrapKind> {
+        self.height = self.height.checked_sub(n).ok_or(TrapKind::StackUnderflow)?;
+        Ok(())
+    }
+
+    fn block_type(&self, bt: &BlockType) -> FuncType {
+        block_type(&self.store.modules[self.module as usize].types, bt)
+    }
+
+    /// The jump for a branch to label `l`, its target patched in later for
+    /// forward branches
+    fn jump(&mut self, l: usize, op: usize, slot: usize) -> Result<Jump, TrapKind> {
+        let i = self
+            .ctrls
+            .len()
+            .checked_sub(l + 1)
+            .ok_or(TrapKind::UnknownLabel)?;
+        let ctrl = &mut self.ctrls[i];
+        let keep = ctrl.label_arity;
+        let drop = self
+            .height
+            .checked_sub(ctrl.height + keep)
+            .ok_or(TrapKind::StackUnderflow)?;
+        let target = match ctrl.start {
+            Some(start) => start,
+            None => {
+                ctrl.fixups.push((op, slot));
+                usize::MAX
+            }
+        };
+        Ok(Jump { target, drop, keep })
+    }
+
+    fn enter_block(&mut self, bt: &BlockType, is_loop: bool) -> Result<(), TrapKind> {
+        let ty = self.block_type(bt);
+        let height = self
+            .height
+            .checked_sub(ty.params.len())
+            .ok_or(TrapKind::StackUnderflow)?;
+        self.ctrls.push(Ctrl {
+            start: is_loop.then_some(self.ops.len()),
+            height,
+            label_arity: if is_loop {
+                ty.params.len()
+            } else {
+                ty.results.len()
+            },
+            results: ty.results.len(),
+            fixups: vec![],
+            unreachable: false,
+        });
+        Ok(())
+    }
+
+    fn end_block(&mut self) {
+        let ctrl = self.ctrls.pop().expect("no block to end");
+        let end = self.ops.len();
+        for (op, slot) in ctrl.fixups {
+            match &mut self.ops[op] {
+                Op::Br(jump) | Op::BrIf(jump) => jump.target = end,
+                Op::BrTable(jumps) => jumps[slot].target = end,
+                Op::BrUnless(target) => *target = end,
+                op => unreachable!("not a branch: {:?}", op),
+            }
+        }
+        self.height = ctrl.height + ctrl.results;
+    }
+
+    fn block(&mut self, instrs: &[Instr]) -> Result<(), TrapKind> {
+        for (i, instr) in instrs.iter().enumerate() {
+            if self.ctrls.last().is_some_and(|ctrl| ctrl.unreachable) {
+                break;
+            }
+            self.path.push(i);
+            self.instr(instr)?;
+            self.path.pop();
+        }
+        Ok(())
+    }
+
+    fn instr(&mut self, instr: &Instr) -> Result<(), TrapKind> {
+        let next = self.ops.len();
+        match instr {
+            Instr::Nop => {}
+            Instr::Unreachable => {
+                self.emit(Op::Unreachable);
+                self.set_unreachable();
+            }
+            &Instr::LocalGet(i) => {
+                self.emit(Op::LocalGet(i));
+                self.height += 1;
+            }
+            &Instr::LocalSet(i) => {
+                self.pop(1)?;
+                self.emit(Op::LocalSet(i));
+            }
+            &Instr::LocalTee(i) => {
+                self.pop(1)?;
+                self.emit(Op::LocalTee(i));
+                self.height += 1;
+            }
+            &Instr::Br(l) => {
+                let jump = self.jump(l, next, 0)?;
+                self.emit(Op::Br(jump));
+                self.set_unreachable();
+            }
+            &Instr::BrIf(l) => {
+                self.pop(1)?;
+                let jump = self.jump(l, next, 0)?;
+                self.emit(Op::BrIf(jump));
+            }
+            Instr::BrTable(ls, default) => {
+                self.pop(1)?;
+                let jumps = ls
+                    .iter()
+                    .chain([default])
+                    .enumerate()
+                    .map(|(slot, &l)| self.jump(l, next, slot))
+                    .collect::<Result<_, _>>()?;
+                self.emit(Op::BrTable(jumps));
+                self.set_unreachable();
+            }
+            Instr::Return => {
+                self.emit(Op::Return);
+                self.set_unreachable();
+            }
+            &Instr::Call(i) => {
+                let addr = self.store.modules[self.module as usize].func_addrs[i];
+                let (FuncInst::Local { func_type, .. } | FuncInst::Host { func_type, .. }) =
+                    &self.funcs[addr as usize];
+                self.pop(func_type.params.len())?;
+                self.emit(Op::Call(addr));
+                self.height += func_type.results.len();
+            }
+            &Instr::CallIndirect(x, y) => {
+                let ty = &self.store.modules[self.module as usize].types[y];
+                self.pop(ty.params.len() + 1)?;
+                self.height += ty.results.len();
+                self.emit(Op::CallIndirect(x, y));
+            }
+            Instr::Block(bt, instrs) => {
+                self.enter_block(bt, false)?;
+                self.block(instrs)?;
+                self.end_block();
+            }
+            Instr::Loop(bt, instrs) => {
+                self.enter_block(bt, true)?;
+                self.block(instrs)?;
+                self.end_block();
+            }
+            Instr::If(bt, then, els) => {
+                self.pop(1)?;
+                let br_unless = self.emit(Op::BrUnless(usize::MAX));
+                self.enter_block(bt, false)?;
+                let entry = self.height;
+                self.block(then)?;
+                if els.is_empty() {
+                    let ctrl = self.ctrls.last_mut().expect("no block to end");
+                    ctrl.fixups.push((br_unless, 0));
+                } else {
+                    // the then branch skips the else branch
+                    let skip = self.emit(Op::Br(Jump {
+                        target: usize::MAX,
+                        drop: 0,
+                        keep: 0,
+                    }));
+                    let ctrl = self.ctrls.last_mut().expect("no block to end");
+                    ctrl.fixups.push((skip, 0));
+                    ctrl.unreachable = false;
+                    self.ops[br_unless] = Op::BrUnless(self.ops.len());
+                    self.height = entry;
+                    self.block(els)?;
+                }
+                self.end_block();
+            }
+            instr => {
+                let (pops, pushes) = stack_effect(instr);
+                self.pop(pops)?;
+                self.emit(Op::Plain(instr.clone()));
+                self.height += pushes;
+            }
+        }
+        Ok(())
+    }
+
+    fn set_unreachable(&mut self) {
+        if let Some(ctrl) = self.ctrls.last_mut() {
+            ctrl.unreachable = true;
+        }
+    }
+}
+
+/// Number of operands popped and pushed by an instruction run by `exec_plain`
+fn stack_effect(instr: &Instr) -> (usize, usize) {
+    use Instr::*;
+    match instr {
+        Drop | GlobalSet(_) => (1, 0),
+        Select(_) => (3, 1),
+        MemoryFill | MemoryCopy | MemoryInit(_) => (3, 0),
+        RefNull(_) | RefFunc(_) | GlobalGet(_) | TableSize(_) | MemorySize => (0, 1),
+        I32Const(_) | I64Const(_) | F32Const(_) | F64Const(_) => (0, 1),
+        TableSet(_) => (2, 0),
+        TableGrow(_) => (2, 1),
+        TableFill(_) | TableCopy(..) | TableInit(..) => (3, 0),
+        ElemDrop(_) | DataDrop(_) => (0, 0),
+        I32Load(_) | I64Load(_) | F32Load(_) | F64Load(_) | I32Load8S(_) | I32Load8U(_)
+        | I32Load16S(_) | I32Load16U(_) | I64Load8S(_) | I64Load8U(_) | I64Load16S(_)
+        | I64Load16U(_) | I64Load32S(_) | I64Load32U(_) => (1, 1),
+        I32Store(_) | I64Store(_) | F32Store(_) | F64Store(_) | I32Store8(_) | I32Store16(_)
+        | I64Store8(_) | I64Store16(_) | I64Store32(_) => (2, 0),
+        RefIsNull | MemoryGrow => (1, 1),
+        I32Eqz | I32Clz | I32Ctz | I32Popcnt | I64Eqz | I64Clz | I64Ctz | I64Popcnt => (1, 1),
+        F32Abs | F32Neg | F32Ceil | F32Floor | F32Trunc | F32Nearest | F32Sqrt => (1, 1),
+        F64Abs | F64Neg | F64Ceil | F64Floor | F64Trunc | F64Nearest | F64Sqrt => (1, 1),
+        I32WrapI64 | I32TruncF32S | I32TruncF32U | I32TruncF64S | I32TruncF64U | I64ExtendI32S
+        | I64ExtendI32U | I64TruncF32S | I64TruncF32U | I64TruncF64S | I64TruncF64U
+        | F32ConvertI32S | F32ConvertI32U | F32ConvertI64S | F32ConvertI64U | F32DemoteF64
+        | F64ConvertI32S | F64ConvertI32U | F64ConvertI64S | F64ConvertI64U | F64PromoteF32
+        | I32ReinterpretF32 | I64ReinterpretF64 | F32ReinterpretI32 | F64ReinterpretI64 => (1, 1),
+        I32Extend8S | I32Extend16S | I64Extend8S | I64Extend16S | I64Extend32S => (1, 1),
+        I32TruncSatF32S | I32TruncSatF32U | I32TruncSatF64S | I32TruncSatF64U | I64TruncSatF32S
+        | I64TruncSatF32U | I64TruncSatF64S | I64TruncSatF64U => (1, 1),
+        // binary operators and comparisons
+        _ => (2, 1),
+    }
+}
+
+struct Frame {
+    func: FuncAddr,
+    module: ModuleAddr,
+    code: Rc<Compiled>,
+    pc: usize,
+    /// Stack index of local 0. The params and declared locals sit on the
+    /// operand stack, below the frame's operands.
+    locals: usize,
+}
+
+/// Interpreter of lowered functions, compiling each on its first call and
+/// keeping the result in the store.
+pub struct FlatVM<'a> {
+    /// Snapshot of `Store::funcs` (see `exec`), giving the types and modules
+    /// of callees
+    funcs: &'a [FuncInst],
+    stack: Vec<Val>,
+    frames: Vec<Frame>,
+    /// `Store::call_depth` on entry
+    base: usize,
+}
+
+impl<'a> FlatVM<'a> {
+    pub fn new(funcs: &'a [FuncInst]) -> Self {
+        FlatVM {
+            funcs,
+            stack: vec![],
+            frames: vec![],
+            base: 0,
+        }
+    }
+
+    pub fn invoke(
+        &mut self,
+        store: &mut Store,
+        addr: FuncAddr,
+        args: Vec<Val>,
+    ) -> Result<Vec<Val>, Trap> {
+        let (FuncInst::Local { func_type, .. } | FuncInst::Host { func_type, .. }) =
+            &self.funcs[addr as usize];
+        let arity = func_type.results.len();
+        self.base = store.call_depth;
+        self.stack.extend(args);
+        self.call(store, addr)
+            .map_err(|kind| Trap::new(kind, Some(addr), vec![]))?;
+        if let Err(kind) = self.run(store) {
+            // the frame is left on the trapping op
+            let frame = self.frames.last().expect("trapped without a frame");
+            let pos = frame.code.pos.get(frame.pc).cloned().unwrap_or_default();
+            return Err(Trap::new(kind, Some(frame.func), pos));
+        }
+        self.stack
+            .pop_vals(arity)
+            .map_err(|kind| Trap::new(kind, Some(addr), vec![]))
+    }
+
+    /// Enter function `addr`, its args on top of the operand stack becoming
+    /// its first locals. Host functions run to completion right away.
+    fn call(&mut self, store: &mut Store, addr: FuncAddr) -> Result<(), TrapKind> {
+        if self.base + self.frames.len() >= store.max_call_depth {
+            return Err(TrapKind::StackExhausted);
+        }
+        let Some(func) = self.funcs.get(addr as usize) else {
+            let args = self.stack.pop_vals(func_type(store, addr).params.len())?;
+            let depth = self.base + self.frames.len();
+            let results = call_unseen(store, depth, args, |funcs, store, args| {
+                FlatVM::new(funcs).invoke(store, addr, args)
+            })?;
+            self.stack.extend(results);
+            return Ok(());
+        };
+        match func {
+            FuncInst::Local {
+                func_type, module, ..
+            } => {
+                let code = self.compiled(store, addr)?;
+                let n_params = func_type.params.len();
+                let locals = self
+                    .stack
+                    .len()
+                    .checked_sub(n_params)
+                    .ok_or(TrapKind::StackUnderflow)?;
+                self.stack
+                    .extend(code.locals[n_params..].iter().map(Val::default_for));
+                self.frames.push(Frame {
+                    func: addr,
+                    module: *module,
+                    code,
+                    pc: 0,
+                    locals,
+                });
+            }
+            FuncInst::Host { func_type, .. } => {
+                let args = self.stack.pop_vals(func_type.params.len())?;
+                let module = self.frames.last().map(|frame| frame.module);
+                let depth = self.base + self.frames.len() + 1;
+                let results = host_call(store, addr, module, depth, &args)?;
+                self.stack.extend(results);
+            }
+        }
+        Ok(())
+    }
+
+    /// The lowered body of local function `addr`, compiled on first use
+    fn compiled(&self, store: &mut Store, addr: FuncAddr) -> Result<Rc<Compiled>, TrapKind> {
+        let i = addr as usize;
+        if let Some(Some(code)) = store.compiled.get(i) {
+            return Ok(code.clone());
+        }
+        let code = Rc::new(compile(store, self.funcs, addr)?);
+        if store.compiled.len() <= i {
+            store.compiled.resize(i + 1, None);
+        }
+        store.compiled[i] = Some(code.clone());
+        Ok(code)
+    }
+
+    /// Run the dispatch loop until all frames returned. On a trap, the top
+    /// frame is left on the trapping op.
+    fn run(&mut self, store: &mut Store) -> Result<(), TrapKind> {
+        // the top frame is kept in locals, and saved to `frames` around calls
+        let Some(frame) = self.frames.last() else {
+            return Ok(());
+        };
+        let mut code = frame.code.clone();
+        let mut pc = frame.pc;
+        let mut locals = frame.locals;
+        let mut module = frame.module;
+
+        macro_rules! load_top {
+            () => {{
+                let Some(frame) = self.frames.last() else {
+                    break Ok(());
+                };
+                code = frame.code.clone();
+                pc = frame.pc;
+                locals = frame.locals;
+                module = frame.module;
+                continue;
+            }};
+        }
+
+        macro_rules! call {
+            ($addr:expr) => {{
+                let addr = $addr;
+                // resume past the call, unless it fails right away
+                self.frames.last_mut().expect("no active frame").pc = pc + 1;
+                if let Err(kind) = self.call(store, addr) {
+                    break Err(kind);
+                }
+                load_top!()
+            }};
+        }
+
+        let result = loop {
+            let Some(op) = code.ops.get(pc) else {
+                // fell off the end, the results stay and the locals and
+                // operands below them go
+                let Some(end) = self
+                    .stack
+                    .len()
+                    .checked_sub(code.arity)
+                    .filter(|&end| end >= locals)
+                else {
+                    break Err(TrapKind::StackUnderflow);
+                };
+                self.stack.drain(locals..end);
+                self.frames.pop();
+                load_top!()
+            };
+            match op {
+                Op::Plain(instr) => {
+                    if let Err(kind) = exec_plain(instr, store, module, &mut self.stack) {
+                        break Err(kind);
+                    }
+                }
+                &Op::LocalGet(i) => {
+                    if i >= code.locals.len() {
+                        break Err(TrapKind::UnknownLocal);
+                    }
+                    let v = self.stack[locals + i].clone();
+                    self.stack.push(v);
+                }
+                &Op::LocalSet(i) | &Op::LocalTee(i) => {
+                    if i >= code.locals.len() {
+                        break Err(TrapKind::UnknownLocal);
+                    }
+                    let v = match op {
+                        Op::LocalSet(_) => self.stack.pop(),
+                        _ => self.stack.last().cloned(),
+                    };
+                    let Some(v) = v else {
+                        break Err(TrapKind::StackUnderflow);
+                    };
+                    self.stack[locals + i] = v;
+                }
+                Op::Br(jump) => {
+                    if let Err(kind) = br(&mut self.stack, jump) {
+                        break Err(kind);
+                    }
+                    pc = jump.target;
+                    continue;
+                }
+                Op::BrIf(jump) => match self.stack.pop_i32() {
+                    Ok(0) => {}
+                    Ok(_) => {
+                        if let Err(kind) = br(&mut self.stack, jump) {
+                            break Err(kind);
+                        }
+                        pc = jump.target;
+                        continue;
+                    }
+                    Err(kind) => break Err(kind),
+                },
+                Op::BrTable(jumps) => {
+                    let i = match self.stack.pop_i32() {
+                        Ok(i) => i as u32 as usize,
+                        Err(kind) => break Err(kind),
+                    };
+                    let jump = jumps.get(i).unwrap_or(&jumps[jumps.len() - 1]);
+                    if let Err(kind) = br(&mut self.stack, jump) {
+                        break Err(kind);
+                    }
+                    pc = jump.target;
+                    continue;
+                }
+                &Op::BrUnless(target) => match self.stack.pop_i32() {
+                    Ok(0) => {
+                        pc = target;
+                        continue;
+                    }
+                    Ok(_) => {}
+                    Err(kind) => break Err(kind),
+                },
+                Op::Return => {
+                    pc = code.ops.len();
+                    continue;
+                }
+                &Op::Call(addr) => call!(addr),
+                &Op::CallIndirect(x, y) => {
+                    let callee = self
+                        .stack
+                        .pop_i32()
+                        .and_then(|i| indirect_callee(store, module, x, y, i as u32));
+                    match callee {
+                        Ok(addr) => call!(addr),
+                        Err(kind) => break Err(kind),
+                    }
+                }
+                Op::Unreachable => break Err(TrapKind::Unreachable),
+            }
+            pc += 1;
+        };
+        if result.is_err() {
+            if let Some(frame) = self.frames.last_mut() {
+                frame.pc = pc;
+            }
+        }
+        result
+    }
+}
+
+fn br(stack: &mut Vec<Val>, jump: &Jump) -> Result<(), TrapKind> {
+    if jump.drop > 0 {
+        let end = stack
+            .len()
+            .checked_sub(jump.keep)
+            .ok_or(TrapKind::StackUnderflow)?;
+        let start = end.checked_sub(jump.drop).ok_or(TrapKind::StackUnderflow)?;
+        stack.drain(start..end);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::func_invoke;
+    use crate::module::testing::*;
+    use std::cell::RefCell;
+
+    fn run(
+        results: Vec<ValType>,
+        locals: Vec<ValType>,
+        body: Vec<Instr>,
+    ) -> Result<Vec<Val>, Trap> {
+        let ty = FuncType {
+            params: vec![],
+            results,
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, locals, body)]);
+        let funcs = store.funcs.clone();
+        FlatVM::new(&funcs).invoke(&mut store, 0, vec![])
+    }
+
+    #[test]
+    fn test_lower() {
+        use Instr::*;
+        let ty = FuncType {
+            params: vec![],
+            results: vec![ValType::I32],
+        };
+        let body = vec![
+            I32Const(1),
+            Block(
+                BlockType::ValTy(ValType::I32),
+                vec![I32Const(2), I32Const(3), Br(0), Drop],
+            ),
+            Loop(BlockType::Empty, vec![Nop, I32Const(0), BrIf(0)]),
+            I32Const(0),
+            If(BlockType::Empty, vec![Br(1)], vec![Return]),
+        ];
+        let store = store_with_funcs(vec![ty], vec![(0, vec![], body)]);
+        let jump = |target, drop, keep| Jump { target, drop, keep };
+        let compiled = compile(&store, &store.funcs, 0).unwrap();
+        assert_eq!(
+            vec![
+                Op::Plain(I32Const(1)),
+                Op::Plain(I32Const(2)),
+                Op::Plain(I32Const(3)),
+                // leaves 3 on top of 1, dead code dropped
+                Op::Br(jump(4, 1, 1)),
+                Op::Plain(I32Const(0)),
+                Op::BrIf(jump(4, 0, 0)),
+                Op::Plain(I32Const(0)),
+                Op::BrUnless(10),
+                // out of the function, keeping the result
+                Op::Br(jump(11, 1, 1)),
+                Op::Br(jump(11, 0, 0)),
+                Op::Return,
+            ],
+            compiled.ops
+        );
+        // ops map back to the instructions they came from
+        assert_eq!(vec![1, 2], compiled.pos[3]);
+        assert_eq!(vec![2, 2], compiled.pos[5]);
+        assert_eq!(vec![4, 0], compiled.pos[8]);
+        assert_eq!(vec![4], compiled.pos[9]);
+        assert_eq!(vec![4, 0], compiled.pos[10]);
+    }
+
+    #[test]
+    fn test_trap_pos() {
+        use Instr::*;
+        let body = vec![Block(
+            BlockType::Empty,
+            vec![
+                Nop,
+                I32Const(1),
+                If(BlockType::Empty, vec![Unreachable], vec![]),
+            ],
+        )];
+        assert_eq!(
+            Err(Trap::new(TrapKind::Unreachable, Some(0), vec![0, 2, 0])),
+            run(vec![], vec![], body)
+        );
+    }
+
+    #[test]
+    fn test_calls() {
+        let mut store = call_indirect_store();
+        let funcs = store.funcs.clone();
+        let mut dispatch = |i| FlatVM::new(&funcs).invoke(&mut store, 0, vec![Val::I32(i)]);
+        assert_eq!(Ok(vec![Val::I32(20)]), dispatch(0));
+        let trap = |kind| Err(Trap::new(kind, Some(0), vec![2]));
+        assert_eq!(trap(TrapKind::IndirectCallTypeMismatch), dispatch(1));
+        assert_eq!(trap(TrapKind::UninitializedElement), dispatch(2));
+        assert_eq!(trap(TrapKind::UndefinedElement), dispatch(3));
+
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut store = host_store(log.clone());
+        let funcs = store.funcs.clone();
+        let result = FlatVM::new(&funcs).invoke(&mut store, 3, vec![]);
+        assert_eq!(Ok(vec![Val::I32(11)]), result);
+        assert_eq!(vec!["hello".to_string()], *log.borrow());
+        let result = FlatVM::new(&funcs).invoke(&mut store, 4, vec![]);
+        assert_eq!(
+            Err(Trap::new(
+                TrapKind::Host("boom".to_string()),
+                Some(4),
+                vec![1]
+            )),
+            result
+        );
+    }
+
+    #[test]
+    fn test_compiled_in_store() {
+        let mut store = loop_store();
+        let fib = |store: &mut Store| func_invoke(store, 2, &[Val::I32(10)]);
+        assert_eq!(Ok(vec![Val::I32(55)]), fib(&mut store));
+        let compiled = store.compiled[2].clone().unwrap();
+        assert!(store.compiled[0].is_none());
+        // later invocations use the store's copy
+        assert_eq!(Ok(vec![Val::I32(55)]), fib(&mut store));
+        assert!(Rc::ptr_eq(&compiled, store.compiled[2].as_ref().unwrap()));
+    }
+
+    #[test]
+    fn test_call_stack_exhausted() {
+        let ty = FuncType {
+            params: vec![],
+            results: vec![],
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, vec![], vec![Instr::Call(0)])]);
+        let funcs = store.funcs.clone();
+        let mut vm = FlatVM::new(&funcs);
+        store.max_call_depth = 100;
+        assert_eq!(
+            Err(Trap::new(TrapKind::StackExhausted, Some(0), vec![0])),
+            vm.invoke(&mut store, 0, vec![])
+        );
+    }
+}
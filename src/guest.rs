@@ -0,0 +1,281 @@
+//! Passing buffers into guests through an allocator the guest exports.
+
+use crate::module::{mem_write, ExternVal, MemAddr, ModuleInst, Store};
+use crate::typed::TypedFunc;
+
+/// Exports a guest may provide for allocating its memory.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AllocExports {
+    /// `alloc: [size] -> [ptr]` and `dealloc: [ptr, size] -> []`
+    AllocDealloc { alloc: String, dealloc: String },
+    /// The component model's `[old_ptr, old_size, align, new_size] -> [ptr]`,
+    /// which can't free memory
+    Realloc(String),
+}
+
+impl AllocExports {
+    /// `cabi_realloc`, then `alloc`/`dealloc`
+    pub fn defaults() -> Vec<AllocExports> {
+        vec![
+            AllocExports::Realloc("cabi_realloc".to_string()),
+            AllocExports::AllocDealloc {
+                alloc: "alloc".to_string(),
+                dealloc: "dealloc".to_string(),
+            },
+        ]
+    }
+}
+
+enum Protocol {
+    AllocDealloc {
+        alloc: TypedFunc<i32, i32>,
+        dealloc: TypedFunc<(i32, i32), ()>,
+    },
+    Realloc(TypedFunc<(i32, i32, i32, i32), i32>),
+}
+
+/// The allocator and memory exported by an instance.
+pub struct GuestAllocator {
+    memory: MemAddr,
+    protocol: Protocol,
+}
+
+impl GuestAllocator {
+    /// Use the first of `protocols` that `inst` exports with the right types,
+    /// allocating in its exported memory `memory`
+    pub fn discover(
+        store: &Store,
+        inst: &ModuleInst,
+        memory: &str,
+        protocols: &[AllocExports],
+    ) -> Result<Self, String> {
+        let Some(ExternVal::Memory(memory)) = inst.get_export(memory) else {
+            return Err(format!("no exported memory {}", memory));
+        };
+        let func = |name: &str| match inst.get_export(name) {
+            Some(ExternVal::Func(addr)) => Some(addr),
+            _ => None,
+        };
+        for exports in protocols {
+            let protocol = match exports {
+                AllocExports::AllocDealloc { alloc, dealloc } => {
+                    let (Some(alloc), Some(dealloc)) = (func(alloc), func(dealloc)) else {
+                        continue;
+                    };
+                    Protocol::AllocDealloc {
+                        alloc: TypedFunc::new(store, alloc)?,
+                        dealloc: TypedFunc::new(store, dealloc)?,
+                    }
+                }
+                AllocExports::Realloc(realloc) => {
+                    let Some(realloc) = func(realloc) else {
+                        continue;
+                    };
+                    Protocol::Realloc(TypedFunc::new(store, realloc)?)
+                }
+            };
+            return Ok(GuestAllocator { memory, protocol });
+        }
+        Err(format!("no allocator exported, tried {:?}", protocols))
+    }
+
+    pub fn memory(&self) -> MemAddr {
+        self.memory
+    }
+
+    /// Allocate `len` bytes aligned to `align`, returning the pointer. `alloc`
+    /// takes no alignment, so it only serves `align` 1. A null pointer for a
+    /// nonempty allocation is the guest running out of memory.
+    pub fn alloc(&self, store: &mut Store, len: u32, align: u32) -> Result<u32, String> {
+        let ptr = match &self.protocol {
+            Protocol::AllocDealloc { .. } if align != 1 => {
+                return Err(format!("alloc can't align to {}", align));
+            }
+            Protocol::AllocDealloc { alloc, .. } => alloc.call(store, len as i32)?,
+            Protocol::Realloc(_) if !align.is_power_of_two() => {
+                return Err(format!("alignment {} is not a power of 2", align));
+            }
+            Protocol::Realloc(realloc) => realloc.call(store, (0, 0, align as i32, len as i32))?,
+        };
+        if ptr == 0 && len > 0 {
+            return Err(format!("guest failed to allocate {} bytes", len));
+        }
+        Ok(ptr as u32)
+    }
+
+    /// Free `len` bytes at `ptr` allocated by `alloc`. Fails with `Realloc`,
+    /// which has no way to free.
+    pub fn dealloc(&self, store: &mut Store, ptr: u32, len: u32) -> Result<(), String> {
+        match &self.protocol {
+            Protocol::AllocDealloc { dealloc, .. } => dealloc.call(store, (ptr as i32, len as i32)),
+            Protocol::Realloc(_) => Err("cabi_realloc can't free memory".to_string()),
+        }
+    }
+
+    /// Copy `bytes` into a fresh allocation, returning its pointer. If the
+    /// guest returned a pointer out of bounds, the allocation is freed again
+    /// where the protocol allows it.
+    pub fn write(&self, store: &mut Store, bytes: &[u8]) -> Result<u32, String> {
+        let len = u32::try_from(bytes.len()).map_err(|_| "buffer too large".to_string())?;
+        let ptr = self.alloc(store, len, 1)?;
+        if let Err(e) = mem_write(store, self.memory, ptr, bytes) {
+            if let Protocol::AllocDealloc { .. } = self.protocol {
+                self.dealloc(store, ptr, len)?;
+            }
+            return Err(format!("guest allocated {} bytes at {}: {}", len, ptr, e));
+        }
+        Ok(ptr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{
+        mem_read, module_instantiate, Code, Export, ExportDesc, Expr, Global, Memory, Module,
+    };
+    use crate::types::{FuncType, GlobalType, Instr, Limits, MemType, Mutability, ValType};
+
+    /// A bump allocator starting at `heap`, exporting `alloc` taking the size
+    /// from local `size_local` of a function of type 0, and a no-op `dealloc`
+    /// of type 1
+    fn bump_module(heap: i32, types: Vec<FuncType>, size_local: usize, alloc: &str) -> Module {
+        let mut module = Module::new();
+        module.types = types;
+        module.functions = vec![0, 1];
+        let code = |instrs| Code {
+            locals: vec![],
+            body: Expr { instrs },
+        };
+        module.code = vec![
+            code(vec![
+                Instr::GlobalGet(0),
+                Instr::GlobalGet(0),
+                Instr::LocalGet(size_local),
+                Instr::I32Add,
+                Instr::GlobalSet(0),
+            ]),
+            code(vec![]),
+        ];
+        module.memories = vec![Memory {
+            mem_type: MemType {
+                limits: Limits { min: 1, max: None },
+            },
+        }];
+        module.globals = vec![Global {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability: Mutability::Var,
+            },
+            init_expr: Expr {
+                instrs: vec![Instr::I32Const(heap)],
+            },
+        }];
+        let export = |name: &str, desc| Export {
+            name: name.to_string(),
+            desc,
+        };
+        module.exports = vec![
+            export("memory", ExportDesc::Memory(0)),
+            export(alloc, ExportDesc::Func(0)),
+            export("dealloc", ExportDesc::Func(1)),
+        ];
+        module
+    }
+
+    fn ty(params: usize, results: usize) -> FuncType {
+        FuncType {
+            params: vec![ValType::I32; params],
+            results: vec![ValType::I32; results],
+        }
+    }
+
+    #[test]
+    fn test_alloc_dealloc() {
+        let mut store = Store::new();
+        let module = bump_module(16, vec![ty(1, 1), ty(2, 0)], 0, "alloc");
+        let inst = module_instantiate(&mut store, &module, &[]).unwrap();
+        let guest =
+            GuestAllocator::discover(&store, &inst, "memory", &AllocExports::defaults()).unwrap();
+        assert_eq!(Ok(16), guest.write(&mut store, b"hello"));
+        assert_eq!(Ok(21), guest.write(&mut store, b"world"));
+        assert_eq!(Ok(&b"helloworld"[..]), mem_read(&store, 0, 16, 10));
+        assert_eq!(Ok(()), guest.dealloc(&mut store, 16, 5));
+        assert_eq!(
+            Err("alloc can't align to 4".to_string()),
+            guest.alloc(&mut store, 8, 4)
+        );
+    }
+
+    #[test]
+    fn test_realloc() {
+        let mut store = Store::new();
+        let module = bump_module(8, vec![ty(4, 1), ty(2, 0)], 3, "cabi_realloc");
+        let inst = module_instantiate(&mut store, &module, &[]).unwrap();
+        let guest =
+            GuestAllocator::discover(&store, &inst, "memory", &AllocExports::defaults()).unwrap();
+        assert_eq!(Ok(8), guest.write(&mut store, &[1, 2, 3]));
+        assert_eq!(Ok(&[1, 2, 3][..]), mem_read(&store, 0, 8, 3));
+        assert_eq!(Ok(11), guest.alloc(&mut store, 4, 4));
+        assert_eq!(
+            Err("alignment 3 is not a power of 2".to_string()),
+            guest.alloc(&mut store, 4, 3)
+        );
+        assert_eq!(
+            Err("cabi_realloc can't free memory".to_string()),
+            guest.dealloc(&mut store, 8, 3)
+        );
+    }
+
+    #[test]
+    fn test_discover_errors() {
+        let mut store = Store::new();
+        let module = bump_module(65534, vec![ty(1, 1), ty(2, 0)], 0, "malloc");
+        let inst = module_instantiate(&mut store, &module, &[]).unwrap();
+        assert_eq!(
+            "no exported memory mem",
+            GuestAllocator::discover(&store, &inst, "mem", &AllocExports::defaults())
+                .err()
+                .unwrap()
+        );
+        assert!(
+            GuestAllocator::discover(&store, &inst, "memory", &AllocExports::defaults())
+                .err()
+                .unwrap()
+                .starts_with("no allocator exported")
+        );
+        // exported under the wrong protocol's name
+        let realloc = [AllocExports::Realloc("malloc".to_string())];
+        assert!(GuestAllocator::discover(&store, &inst, "memory", &realloc)
+            .err()
+            .unwrap()
+            .starts_with("function 0 has type"));
+
+        let custom = [AllocExports::AllocDealloc {
+            alloc: "malloc".to_string(),
+            dealloc: "dealloc".to_string(),
+        }];
+        let guest = GuestAllocator::discover(&store, &inst, "memory", &custom).unwrap();
+        // the guest hands out memory past the end
+        assert_eq!(
+            Err("guest allocated 4 bytes at 65534: memory access out of bounds".to_string()),
+            guest.write(&mut store, &[0; 4])
+        );
+    }
+
+    #[test]
+    fn test_alloc_null() {
+        // the first allocation from a heap at 0 looks like a failed one
+        let mut store = Store::new();
+        let module = bump_module(0, vec![ty(1, 1), ty(2, 0)], 0, "alloc");
+        let inst = module_instantiate(&mut store, &module, &[]).unwrap();
+        let guest =
+            GuestAllocator::discover(&store, &inst, "memory", &AllocExports::defaults()).unwrap();
+        assert_eq!(Ok(0), guest.write(&mut store, &[]));
+        assert_eq!(
+            Err("guest failed to allocate 2 bytes".to_string()),
+            guest.write(&mut store, b"hi")
+        );
+        assert_eq!(Ok(&[0, 0][..]), mem_read(&store, 0, 0, 2));
+    }
+}
@@ -0,0 +1,221 @@
+//! Name-based import resolution, in the manner of the spec harness's
+//! `register` command.
+
+use std::collections::HashMap;
+
+use crate::module::{
+    extern_type, extern_type_matches, module_instantiate, ExternVal, Module, ModuleInst, Store,
+};
+use crate::typed::{func_wrap, IntoHostFunc};
+
+/// Definitions by module and field name, to be matched against imports.
+#[derive(Debug, Clone, Default)]
+pub struct Linker {
+    defs: HashMap<(String, String), ExternVal>,
+}
+
+impl Linker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Define `module.name`, shadowing an earlier definition
+    pub fn define(&mut self, module: &str, name: &str, val: ExternVal) -> &mut Self {
+        self.defs
+            .insert((module.to_string(), name.to_string()), val);
+        self
+    }
+
+    /// Define `module.name` as a host function backed by `func`
+    pub fn func_wrap<P, R, F: IntoHostFunc<P, R>>(
+        &mut self,
+        store: &mut Store,
+        module: &str,
+        name: &str,
+        func: F,
+    ) -> &mut Self {
+        let addr = func_wrap(store, name, func);
+        self.define(module, name, ExternVal::Func(addr))
+    }
+
+    /// Define all exports of `inst` under `module`, shadowing earlier
+    /// definitions of the same names
+    pub fn instance(&mut self, module: &str, inst: &ModuleInst) -> &mut Self {
+        for export in &inst.exports {
+            self.define(module, &export.name, export.value.clone());
+        }
+        self
+    }
+
+    pub fn get(&self, module: &str, name: &str) -> Option<&ExternVal> {
+        self.defs.get(&(module.to_string(), name.to_string()))
+    }
+
+    /// The definitions for the imports of `module`, in order
+    pub fn resolve(&self, store: &Store, module: &Module) -> Result<Vec<ExternVal>, String> {
+        module
+            .module_imports()
+            .into_iter()
+            .map(|(module_name, name, expected)| {
+                let Some(val) = self.get(&module_name, &name) else {
+                    return Err(self.unknown_import(&module_name, &name));
+                };
+                let actual = extern_type(store, val);
+                if !extern_type_matches(&actual, &expected) {
+                    return Err(format!(
+                        "incompatible import type for {}.{}: expected {:?}, got {:?}",
+                        module_name, name, expected, actual
+                    ));
+                }
+                Ok(val.clone())
+            })
+            .collect()
+    }
+
+    /// Instantiate `module` with its imports resolved by name
+    pub fn instantiate(&self, store: &mut Store, module: &Module) -> Result<ModuleInst, String> {
+        let imports = self.resolve(store, module)?;
+        module_instantiate(store, module, &imports)
+    }
+
+    fn unknown_import(&self, module: &str, name: &str) -> String {
+        let mut names: Vec<&str> = self
+            .defs
+            .keys()
+            .filter(|(m, _)| m == module)
+            .map(|(_, n)| n.as_str())
+            .collect();
+        if names.is_empty() {
+            return format!(
+                "unknown import {}.{}: no module {} defined",
+                module, name, module
+            );
+        }
+        names.sort_unstable();
+        format!(
+            "unknown import {}.{}: module {} defines {}",
+            module,
+            name,
+            module,
+            names.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::{func_invoke, Code, Export, ExportDesc, Expr, Import, ImportDesc, Memory};
+    use crate::types::{FuncType, Instr, Limits, MemType, Val, ValType};
+
+    /// A module exporting `get`, a function `[] -> [i32]` returning `n`
+    fn constant(n: i32) -> Module {
+        let mut module = Module::new();
+        module.types = vec![FuncType {
+            params: vec![],
+            results: vec![ValType::I32],
+        }];
+        module.functions = vec![0];
+        module.code = vec![Code {
+            locals: vec![],
+            body: Expr {
+                instrs: vec![Instr::I32Const(n)],
+            },
+        }];
+        module.exports = vec![Export {
+            name: "get".to_string(),
+            desc: ExportDesc::Func(0),
+        }];
+        module
+    }
+
+    /// A module importing `env.get`, and exporting it as `get` after adding 100
+    fn client() -> Module {
+        let mut module = constant(0);
+        module.imports = vec![Import {
+            module: "env".to_string(),
+            name: "get".to_string(),
+            desc: ImportDesc::Func(0),
+        }];
+        module.code[0].body.instrs = vec![Instr::Call(0), Instr::I32Const(100), Instr::I32Add];
+        module.exports[0].desc = ExportDesc::Func(1);
+        module
+    }
+
+    fn call_get(store: &mut Store, inst: &ModuleInst) -> Result<Vec<Val>, String> {
+        let Some(ExternVal::Func(addr)) = inst.exports.first().map(|e| e.value.clone()) else {
+            panic!("no get export");
+        };
+        func_invoke(store, addr, &[])
+    }
+
+    #[test]
+    fn test_resolve_instance() {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        let one = linker.instantiate(&mut store, &constant(1)).unwrap();
+        linker.instance("env", &one);
+        let inst = linker.instantiate(&mut store, &client()).unwrap();
+        assert_eq!(Ok(vec![Val::I32(101)]), call_get(&mut store, &inst));
+
+        // shadowing only affects later instantiations
+        let two = linker.instantiate(&mut store, &constant(2)).unwrap();
+        linker.instance("env", &two);
+        let shadowed = linker.instantiate(&mut store, &client()).unwrap();
+        assert_eq!(Ok(vec![Val::I32(102)]), call_get(&mut store, &shadowed));
+        assert_eq!(Ok(vec![Val::I32(101)]), call_get(&mut store, &inst));
+    }
+
+    #[test]
+    fn test_resolve_host_func() {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        linker.func_wrap(&mut store, "env", "get", || 7);
+        let inst = linker.instantiate(&mut store, &client()).unwrap();
+        assert_eq!(Ok(vec![Val::I32(107)]), call_get(&mut store, &inst));
+    }
+
+    #[test]
+    fn test_resolve_errors() {
+        let mut store = Store::new();
+        let mut linker = Linker::new();
+        assert_eq!(
+            Err("unknown import env.get: no module env defined".to_string()),
+            linker.resolve(&store, &client())
+        );
+
+        linker.func_wrap(&mut store, "env", "put", |_: i32| {});
+        linker.func_wrap(&mut store, "env", "drop", || {});
+        assert_eq!(
+            Err("unknown import env.get: module env defines drop, put".to_string()),
+            linker.resolve(&store, &client())
+        );
+
+        linker.func_wrap(&mut store, "env", "get", || 1i64);
+        assert_eq!(
+            Err("incompatible import type for env.get: \
+                 expected Func(FuncType { params: [], results: [I32] }), \
+                 got Func(FuncType { params: [], results: [I64] })"
+                .to_string()),
+            linker.resolve(&store, &client())
+        );
+
+        // a memory under a function's name
+        let mut exporter = Module::new();
+        exporter.memories = vec![Memory {
+            mem_type: MemType {
+                limits: Limits { min: 0, max: None },
+            },
+        }];
+        exporter.exports = vec![Export {
+            name: "get".to_string(),
+            desc: ExportDesc::Memory(0),
+        }];
+        let inst = linker.instantiate(&mut store, &exporter).unwrap();
+        linker.instance("env", &inst);
+        assert!(linker
+            .resolve(&store, &client())
+            .unwrap_err()
+            .starts_with("incompatible import type for env.get"));
+    }
+}
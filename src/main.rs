@@ -1,27 +1,34 @@
 mod binary;
 mod cont;
+mod diff;
+mod exec;
+mod flat;
+mod guest;
+mod linker;
+mod memory;
 mod module;
+mod slots;
+mod trap;
+mod typed;
 mod types;
 
+use exec::Operands;
+use module::{FuncAddr, FuncInst, ModuleAddr, Store};
+use slots::{Repr, Slot};
+use trap::{Trap, TrapKind};
 use types::*;
 
-enum Label {
-    Empty(usize, Vec<usize>),
-    Continuation(usize, Vec<usize>),
-}
-
-impl Label {
-    fn num_rets(&self) -> usize {
-        match self {
-            Self::Empty(n, _) => n.clone(),
-            Self::Continuation(n, _) => n.clone(),
-        }
-    }
-}
-
-enum StackItem {
-    Val(Val),
-    Label(Label),
+/// A block entered and not yet left. Labels live on their own stack, apart
+/// from the operands.
+#[derive(Debug, Clone, Copy)]
+struct Label {
+    /// Operands taken by a branch: the block's results, or a loop's params
+    arity: usize,
+    /// Operand height below the block's params
+    height: usize,
+    /// Cursor length on the block instr, which a branch returns to
+    depth: usize,
+    is_loop: bool,
 }
 
 fn nested_instrs(instr: &Instr) -> &Vec<Instr> {
@@ -32,62 +39,18 @@ fn nested_instrs(instr: &Instr) -> &Vec<Instr> {
     }
 }
 
-macro_rules! impl_stack_push {
-    ($($type:ty, $fn_name:ident, $variant:ident),* $(,)?) => {
-        $(
-            fn $fn_name(&mut self, v: $type) {
-                self.push(StackItem::Val(Val::$variant(v)))
-            }
-        )*
-    };
-}
-
-macro_rules! impl_stack_pop {
-    ($($type:ty, $fn_name:ident, $variant:ident),* $(,)?) => {
-        $(
-            fn $fn_name(&mut self) -> $type {
-                if let StackItem::Val(Val::$variant(i)) = self.pop() {
-                    i
-                } else {
-                    panic!("")
-                }
-            }
-        )*
-    }
-}
-
-macro_rules! stack_val {
-    ($expr:expr) => {
-        match $expr {
-            StackItem::Val(v) => v,
-            _ => panic!("impossible"),
-        }
-    };
-}
-
-fn block_instrs(instr: &Instr) -> &Vec<Instr> {
-    match instr {
-        Instr::Block(_, instrs) => instrs,
-        Instr::Loop(_, instrs) => instrs,
-        _ => panic!("unreachable"),
-    }
-}
-
-fn n_func_rets(ty: &Ty) -> usize {
-    match ty {
-        Ty::Func(_, rets) => rets.len(),
-        _ => panic!("impossible"),
-    }
+fn n_func_rets(ty: &FuncType) -> usize {
+    ty.results.len()
 }
 
 struct Level<'a> {
     cur: usize,
     len: usize,
-    instrs: &'a Vec<Instr>,
+    instrs: &'a [Instr],
 }
 
 impl<'a> Level<'a> {
-    fn new(instrs: &'a Vec<Instr>) -> Self {
+    fn new(instrs: &'a [Instr]) -> Self {
         Level {
             cur: 0,
             len: instrs.len(),
@@ -102,10 +65,13 @@ impl<'a> Level<'a> {
 
 trait InstrCursor<'a> {
     fn instr(&self) -> Option<&'a Instr>;
-    fn next(&mut self);
+    /// Move to the next instr, returning the number of blocks left.
+    fn next(&mut self) -> usize;
     fn pos(&self) -> Vec<usize>;
-    fn seek(&mut self, pos: &Vec<usize>);
-    fn push_instrs(&mut self, instrs: &'a Vec<Instr>);
+    fn seek(&mut self, pos: &[usize]);
+    /// Enter a block body, returning the number of blocks left, which is
+    /// only nonzero when the body is empty.
+    fn push_instrs(&mut self, instrs: &'a [Instr]) -> usize;
 }
 
 impl<'a> InstrCursor<'a> for Vec<Level<'a>> {
@@ -113,219 +79,399 @@ impl<'a> InstrCursor<'a> for Vec<Level<'a>> {
         self.last().map(|l| l.instr())
     }
 
-    fn next(&mut self) {
+    fn next(&mut self) -> usize {
+        let mut n_ended = 0;
         while let Some(r) = self.last_mut() {
             r.cur += 1;
             if r.cur == r.len {
                 self.pop();
+                n_ended += 1;
             } else {
                 break;
             }
         }
+        n_ended
     }
 
-    fn seek(&mut self, pos: &Vec<usize>) {
-        // most likely jump to a different instr at some higher level
-        for i in 0..self.len() {
-            if self[i].cur != pos[i] {
-                self.truncate(i);
-                break;
-            }
-        }
-        if self.len() > pos.len() {
-            panic!("impossible");
-        }
-        for i in self.len()..pos.len() {
-            self.push_instrs(block_instrs(self[i].instr()))
-        }
+    fn seek(&mut self, pos: &[usize]) {
+        // labels only point at enclosing block instrs, so the target
+        // position is always a prefix of the current one
+        self.truncate(pos.len());
     }
 
     fn pos(&self) -> Vec<usize> {
         self.iter().map(|l| l.cur).collect()
     }
 
-    fn push_instrs(&mut self, instrs: &'a Vec<Instr>) {
-        self.push(Level::new(instrs))
+    fn push_instrs(&mut self, instrs: &'a [Instr]) -> usize {
+        if instrs.is_empty() {
+            // nothing to enter, the block ends and we move past its instr
+            1 + self.next()
+        } else {
+            self.push(Level::new(instrs));
+            0
+        }
     }
 }
 
-struct VM {
-    stack: Vec<StackItem>,
+/// Activation of a local function.
+struct Frame<'a, V> {
+    func: FuncAddr,
+    module: ModuleAddr,
+    /// Params followed by the declared locals
+    locals: Vec<V>,
+    arity: usize,
+    /// Operand stack height below the args
+    height: usize,
+    /// Label stack height below the function's own label
+    labels: usize,
+    cursor: Vec<Level<'a>>,
+}
+
+/// Tree-walking interpreter, holding values as `V`: tagged `Val`s checked on
+/// every pop, or untagged `Slot`s for code known to be valid.
+struct VM<'a, V = Val> {
+    /// Snapshot of `Store::funcs` (see `exec`), the cursors borrow bodies from
+    funcs: &'a [FuncInst],
+    /// Operands of all frames
+    stack: Vec<V>,
+    labels: Vec<Label>,
+    frames: Vec<Frame<'a, V>>,
+    /// `Store::call_depth` on entry
+    base: usize,
     halt: bool,
-    // move this into stack frame
-    locals: Vec<Val>,
-    types: Vec<Ty>,
 }
 
-impl VM {
-    fn new() -> VM {
+impl<'a> VM<'a> {
+    fn new(funcs: &'a [FuncInst]) -> Self {
+        VM::with_funcs(funcs)
+    }
+}
+
+impl<'a> VM<'a, Slot> {
+    /// Untagged operands, which only code that passed validation may run on:
+    /// ill-typed code computes garbage instead of trapping. Nothing validates
+    /// modules yet, so only the benchmarks use it.
+    #[cfg(test)]
+    fn unchecked(funcs: &'a [FuncInst]) -> Self {
+        VM::with_funcs(funcs)
+    }
+}
+
+impl<'a, V: Repr> VM<'a, V>
+where
+    Vec<V>: Operands,
+{
+    fn with_funcs(funcs: &'a [FuncInst]) -> Self {
         VM {
+            funcs,
             stack: vec![],
+            labels: vec![],
+            frames: vec![],
+            base: 0,
             halt: false, // should be a thread state
-            locals: vec![Val::I32(0)],
-            types: vec![],
         }
     }
 
-    fn run(&mut self, instrs: &Vec<Instr>) {
-        let mut cursor = if instrs.len() > 0 {
-            vec![Level::new(instrs)]
-        } else {
-            Vec::new()
+    fn invoke(
+        &mut self,
+        store: &mut Store,
+        addr: FuncAddr,
+        args: Vec<Val>,
+    ) -> Result<Vec<Val>, Trap> {
+        let results = match &self.funcs[addr as usize] {
+            FuncInst::Local { func_type, .. } | FuncInst::Host { func_type, .. } => {
+                &func_type.results
+            }
         };
-        while let Some(instr) = cursor.instr() {
-            self.step(instr, &mut cursor);
+        self.base = store.call_depth;
+        self.stack.extend(args.iter().map(V::from_val));
+        self.call(store, addr)
+            .map_err(|kind| Trap::new(kind, Some(addr), vec![]))?;
+        while let Some(frame) = self.frames.last() {
+            let result = match frame.cursor.instr() {
+                // fell off the end of the body
+                None => self.ret(),
+                Some(instr) => self.step(store, instr),
+            };
+            // a failed step leaves the cursor on the trapping instruction
+            if let Err(kind) = result {
+                let frame = self.frame();
+                return Err(Trap::new(kind, Some(frame.func), frame.cursor.pos()));
+            }
         }
+        slots::pop_typed_vals(&mut self.stack, results)
+            .map_err(|kind| Trap::new(kind, Some(addr), vec![]))
     }
 
-    fn step<'a>(&mut self, instr: &'a Instr, cursor: &mut Vec<Level<'a>>) {
+    fn step(&mut self, store: &mut Store, instr: &'a Instr) -> Result<(), TrapKind> {
         // most instrs moves cursor to next, so we factor out a boolean
         let mut cursor_updated = false;
         // execute instr
         match instr {
-            Instr::Unreachable => {}
+            Instr::Unreachable => return Err(TrapKind::Unreachable),
             Instr::Nop => {}
-            Instr::Drop => {
-                todo!("drop instruction")
+            &Instr::LocalSet(i) => {
+                let v = self.pop()?;
+                *self.local(i)? = v;
             }
-            Instr::I32Const(i) => self.push_i32(i.clone()),
-            Instr::I32Add => {
-                let i1 = self.pop_i32();
-                let i2 = self.pop_i32();
-                self.push_i32(i1 + i2);
+            &Instr::LocalTee(i) => {
+                let v = self.stack.last().ok_or(TrapKind::StackUnderflow)?.clone();
+                *self.local(i)? = v;
             }
-            Instr::I32Sub => {
-                todo!("i32.sub instruction")
+            &Instr::LocalGet(i) => {
+                let v = self.local(i)?.clone();
+                self.stack.push(v);
             }
-            Instr::I32Mul => {
-                todo!("i32.mul instruction")
+            &Instr::Br(l) => {
+                self.br(l)?;
+                cursor_updated = true;
             }
-            Instr::I32Eq => {
-                let a = self.pop_i32();
-                let b = self.pop_i32();
-                if a == b {
-                    self.push_i32(1);
-                } else {
-                    self.push_i32(0);
+            &Instr::BrIf(l) => {
+                if self.stack.pop_i32()? != 0 {
+                    self.br(l)?;
+                    cursor_updated = true;
                 }
             }
-            &Instr::LocalSet(i) => {
-                let v = stack_val!(self.pop());
-                self.locals[i] = v.clone();
+            Instr::BrTable(ls, default) => {
+                let i = self.stack.pop_i32()? as u32 as usize;
+                self.br(*ls.get(i).unwrap_or(default))?;
+                cursor_updated = true;
             }
-            &Instr::LocalTee(i) => {
-                let v = stack_val!(self.pop());
-                self.locals[i] = v.clone();
-                self.push(StackItem::Val(v));
+            Instr::Return => {
+                self.ret()?;
+                cursor_updated = true;
             }
-            &Instr::LocalGet(i) => {
-                self.push(StackItem::Val(self.locals[i].clone()));
+            &Instr::Call(i) => {
+                let addr = store.modules[self.frame().module as usize].func_addrs[i];
+                // the caller moves past the call when the callee returns
+                self.call(store, addr)?;
+                cursor_updated = true;
             }
-            &Instr::Br(l) => {
-                let (label, vals) = self.pop_label(l);
-                vals.into_iter().for_each(|v| self.push(StackItem::Val(v)));
-                match label {
-                    Label::Empty(_, pos) => {
-                        cursor.seek(&pos);
-                        cursor.next();
-                    }
-                    Label::Continuation(_, pos) => {
-                        cursor.seek(&pos);
-                    }
-                }
+            &Instr::CallIndirect(x, y) => {
+                let i = self.stack.pop_i32()? as u32;
+                let addr = exec::indirect_callee(store, self.frame().module, x, y, i)?;
+                self.call(store, addr)?;
                 cursor_updated = true;
             }
             Instr::Loop(bt, instrs) => {
-                self.push(StackItem::Label(Label::Continuation(
-                    block_type(&self.types, bt).func_tys().0.len(),
-                    cursor.pos(),
-                )));
-                cursor.push_instrs(instrs);
+                let n_args = self.block_type(store, bt).params.len();
+                self.enter_block(n_args, n_args, true, instrs)?;
                 cursor_updated = true;
             }
             Instr::Block(bt, instrs) => {
-                self.push(StackItem::Label(Label::Empty(
-                    block_type(&self.types, bt).func_tys().1.len(),
-                    cursor.pos(),
-                )));
-                cursor.push_instrs(instrs);
+                let bt = self.block_type(store, bt);
+                self.enter_block(bt.results.len(), bt.params.len(), false, instrs)?;
                 cursor_updated = true;
             }
             Instr::If(bt, instrs_then, instrs_else) => {
-                let b = self.pop_i32();
-                self.push(StackItem::Label(Label::Empty(
-                    block_type(&self.types, bt).func_tys().1.len(),
-                    cursor.pos(),
-                )));
-                if b != 0 {
-                    cursor.push_instrs(instrs_then);
-                } else {
-                    cursor.push_instrs(instrs_else);
-                }
+                let b = self.stack.pop_i32()?;
+                let bt = self.block_type(store, bt);
+                let instrs = if b != 0 { instrs_then } else { instrs_else };
+                self.enter_block(bt.results.len(), bt.params.len(), false, instrs)?;
                 cursor_updated = true;
             }
+            instr => {
+                let module = self.frame().module;
+                exec::exec_plain(instr, store, module, &mut self.stack)?
+            }
         }
         if !cursor_updated {
-            cursor.next();
+            let n_ended = self.frame().cursor.next();
+            self.end_blocks(n_ended);
         }
+        Ok(())
     }
 
-    fn result(&self) -> Option<Val> {
-        if let Some(StackItem::Val(v)) = self.stack.last() {
-            Some(v.clone())
-        } else {
-            None
+    /// Enter a block body, its label taking the `n_args` operands on top.
+    fn enter_block(
+        &mut self,
+        arity: usize,
+        n_args: usize,
+        is_loop: bool,
+        instrs: &'a [Instr],
+    ) -> Result<(), TrapKind> {
+        let height = self
+            .stack
+            .len()
+            .checked_sub(n_args)
+            .ok_or(TrapKind::StackUnderflow)?;
+        // the args can't come from an enclosing block's params
+        if self
+            .labels
+            .last()
+            .is_some_and(|label| height < label.height)
+        {
+            return Err(TrapKind::StackUnderflow);
         }
+        let depth = self.frame().cursor.len();
+        self.labels.push(Label {
+            arity,
+            height,
+            depth,
+            is_loop,
+        });
+        let n_ended = self.frame().cursor.push_instrs(instrs);
+        self.end_blocks(n_ended);
+        Ok(())
     }
 
-    #[inline]
-    fn push(&mut self, item: StackItem) {
-        self.stack.push(item);
+    /// Branch to the `l`th enclosing label.
+    fn br(&mut self, l: usize) -> Result<(), TrapKind> {
+        let label = self.pop_label(l)?;
+        let cursor = &mut self.frame().cursor;
+        // back on the block instr
+        cursor.truncate(label.depth);
+        if !label.is_loop {
+            let n_ended = cursor.next();
+            self.end_blocks(n_ended);
+        }
+        Ok(())
     }
 
-    #[inline]
-    fn pop(&mut self) -> StackItem {
-        self.stack.pop().unwrap()
+    /// Drop the labels of the `n` innermost blocks, which ended without a branch.
+    /// Their results stay on the stack.
+    fn end_blocks(&mut self, n: usize) {
+        self.labels.truncate(self.labels.len() - n);
     }
 
-    impl_stack_push!(
-        i32, push_i32, I32, i64, push_i64, I64, f32, push_f32, F32, f64, push_f64, F64,
-    );
-
-    impl_stack_pop!(i32, pop_i32, I32, i64, pop_i64, I64, f32, pop_f32, F32, f64, pop_f64, F64,);
-
-    fn pop_label(&mut self, mut n_labels: usize) -> (Label, Vec<Val>) {
-        let mut i_lbl = None;
-        for i in (0..self.stack.len()).rev() {
-            if let StackItem::Label(lbl) = &self.stack[i] {
-                if n_labels == 0 {
-                    i_lbl = Some(i);
+    /// Enter function `addr`, taking its args from the operand stack.
+    /// Host functions run to completion right away.
+    fn call(&mut self, store: &mut Store, addr: FuncAddr) -> Result<(), TrapKind> {
+        if self.base + self.frames.len() >= store.max_call_depth {
+            return Err(TrapKind::StackExhausted);
+        }
+        let funcs = self.funcs;
+        let Some(func) = funcs.get(addr as usize) else {
+            let args =
+                slots::pop_typed_vals(&mut self.stack, &module::func_type(store, addr).params)?;
+            let depth = self.base + self.frames.len();
+            let results = exec::call_unseen(store, depth, args, |funcs, store, args| {
+                VM::with_funcs(funcs).invoke(store, addr, args)
+            })?;
+            self.stack.extend(results.iter().map(V::from_val));
+            self.resume_caller();
+            return Ok(());
+        };
+        match func {
+            FuncInst::Local {
+                func_type,
+                module,
+                code,
+            } => {
+                let height = self
+                    .stack
+                    .len()
+                    .checked_sub(func_type.params.len())
+                    .ok_or(TrapKind::StackUnderflow)?;
+                let mut locals = self.stack.split_off(height);
+                locals.extend(
+                    code.locals
+                        .iter()
+                        .map(|ty| V::from_val(&Val::default_for(ty))),
+                );
+                let arity = func_type.results.len();
+                let labels = self.labels.len();
+                // the body is a block, `br` to it returns
+                self.labels.push(Label {
+                    arity,
+                    height,
+                    depth: 0,
+                    is_loop: false,
+                });
+                // an empty body has no level, `ret` drops the label with the frame
+                let mut cursor = vec![];
+                if !code.body.instrs.is_empty() {
+                    cursor.push(Level::new(&code.body.instrs));
                 }
-                n_labels -= 1;
+                self.frames.push(Frame {
+                    func: addr,
+                    module: *module,
+                    locals,
+                    arity,
+                    height,
+                    labels,
+                    cursor,
+                });
+            }
+            FuncInst::Host { func_type, .. } => {
+                let args = slots::pop_typed_vals(&mut self.stack, &func_type.params)?;
+                let module = self.frames.last().map(|frame| frame.module);
+                let depth = self.base + self.frames.len() + 1;
+                let results = module::host_call(store, addr, module, depth, &args)?;
+                self.stack.extend(results.iter().map(V::from_val));
+                self.resume_caller();
             }
         }
-        let i_lbl = i_lbl.unwrap();
-        let n_vals = if let StackItem::Label(lbl) = &self.stack[i_lbl] {
-            lbl.num_rets()
-        } else {
-            panic!("impossible")
+        Ok(())
+    }
+
+    /// Leave the current frame, keeping its results on top of the caller's operands.
+    fn ret(&mut self) -> Result<(), TrapKind> {
+        let (arity, height, labels) = {
+            let frame = self.frame();
+            (frame.arity, frame.height, frame.labels)
         };
-        let vals = self
+        self.keep_top(arity, height)?;
+        self.labels.truncate(labels);
+        self.frames.pop();
+        self.resume_caller();
+        Ok(())
+    }
+
+    /// Move the caller past its call instr once the callee returned.
+    fn resume_caller(&mut self) {
+        if let Some(caller) = self.frames.last_mut() {
+            let n_ended = caller.cursor.next();
+            self.end_blocks(n_ended);
+        }
+    }
+
+    #[inline]
+    fn frame(&mut self) -> &mut Frame<'a, V> {
+        self.frames.last_mut().expect("no active frame")
+    }
+
+    fn block_type(&mut self, store: &Store, bt: &BlockType) -> FuncType {
+        block_type(&store.modules[self.frame().module as usize].types, bt)
+    }
+
+    fn local(&mut self, i: usize) -> Result<&mut V, TrapKind> {
+        self.frame().locals.get_mut(i).ok_or(TrapKind::UnknownLocal)
+    }
+
+    #[inline]
+    fn pop(&mut self) -> Result<V, TrapKind> {
+        self.stack.pop().ok_or(TrapKind::StackUnderflow)
+    }
+
+    /// Drop the operands between `height` and the top `n`.
+    fn keep_top(&mut self, n: usize, height: usize) -> Result<(), TrapKind> {
+        let start = self
             .stack
-            .drain((self.stack.len() - n_vals)..)
-            .map(|i| match i {
-                StackItem::Val(v) => v,
-                _ => panic!("impossible"),
-            })
-            .collect::<Vec<Val>>();
-        self.stack.truncate(i_lbl + 1);
-        let label = {
-            match self.stack.pop() {
-                Some(StackItem::Label(lbl)) => lbl,
-                _ => panic!("impossible"),
-            }
-        };
-        (label, vals)
+            .len()
+            .checked_sub(n)
+            .filter(|&start| start >= height)
+            .ok_or(TrapKind::StackUnderflow)?;
+        self.stack.drain(height..start);
+        Ok(())
+    }
+
+    /// Leave the `n_labels + 1` innermost blocks, keeping the operands taken
+    /// by the last one.
+    fn pop_label(&mut self, n_labels: usize) -> Result<Label, TrapKind> {
+        // labels of the callers are out of reach
+        let labels = self.frame().labels;
+        let i = self
+            .labels
+            .len()
+            .checked_sub(n_labels + 1)
+            .filter(|&i| i >= labels)
+            .ok_or(TrapKind::UnknownLabel)?;
+        let label = self.labels[i];
+        self.keep_top(label.arity, label.height)?;
+        self.labels.truncate(i);
+        Ok(label)
     }
 }
 
@@ -336,45 +482,402 @@ fn main() {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use module::testing::{call_indirect_store, host_store, loop_store, store_with_funcs};
+    use module::Ref;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Run `body` as a function `[] -> results` with declared `locals`.
+    fn run(
+        results: Vec<ValType>,
+        locals: Vec<ValType>,
+        body: Vec<Instr>,
+    ) -> Result<Vec<Val>, Trap> {
+        let ty = FuncType {
+            params: vec![],
+            results,
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, locals, body)]);
+        let funcs = store.funcs.clone();
+        VM::new(&funcs).invoke(&mut store, 0, vec![])
+    }
 
     #[test]
     pub fn test() {
-        let mut vm = VM::new();
-        vm.run(&vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add]);
-        assert_eq!(Some(Val::I32(2)), vm.result());
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
+        );
+        assert_eq!(Ok(vec![Val::I32(2)]), result);
     }
 
     #[test]
     pub fn test_block() {
-        let mut vm = VM::new();
-        vm.run(&vec![Instr::Block(
-            BlockType::ValTy(Ty::I32),
-            vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
-        )]);
-        assert_eq!(Some(Val::I32(2)), vm.result());
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::Block(
+                BlockType::ValTy(ValType::I32),
+                vec![Instr::I32Const(1), Instr::I32Const(1), Instr::I32Add],
+            )],
+        );
+        assert_eq!(Ok(vec![Val::I32(2)]), result);
     }
 
     #[test]
-    pub fn test_loop() {
-        let mut vm = VM::new();
-        vm.run(&vec![
-            Instr::Block(
+    pub fn test_block_type_index() {
+        // type section: [] -> [i32]
+        let (_, types) =
+            binary::sections::parse_type_section(&[0x01, 0x60, 0x00, 0x01, 0x7F]).unwrap();
+        let body = vec![Instr::Block(
+            BlockType::Index(0),
+            vec![Instr::I32Const(2), Instr::I32Const(3), Instr::I32Add],
+        )];
+        let mut store = store_with_funcs(types, vec![(0, vec![], body)]);
+        let funcs = store.funcs.clone();
+        let result = VM::new(&funcs).invoke(&mut store, 0, vec![]);
+        assert_eq!(Ok(vec![Val::I32(5)]), result);
+    }
+
+    #[test]
+    pub fn test_ref_is_null() {
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::RefNull(RefType::ExternRef), Instr::RefIsNull],
+        );
+        assert_eq!(Ok(vec![Val::I32(1)]), result);
+    }
+
+    #[test]
+    pub fn test_ref_local() {
+        let result = run(
+            vec![ValType::FuncRef],
+            vec![ValType::FuncRef],
+            vec![Instr::LocalGet(0)],
+        );
+        assert_eq!(Ok(vec![Val::Ref(Ref::Null(RefType::FuncRef))]), result);
+    }
+
+    #[test]
+    pub fn test_unreachable() {
+        let result = run(
+            vec![],
+            vec![],
+            vec![Instr::Block(
                 BlockType::Empty,
-                vec![Instr::Loop(
+                vec![Instr::Nop, Instr::Unreachable],
+            )],
+        );
+        assert_eq!(
+            Err(Trap::new(TrapKind::Unreachable, Some(0), vec![0, 1])),
+            result
+        );
+    }
+
+    #[test]
+    pub fn test_stack_underflow() {
+        let result = run(vec![], vec![], vec![Instr::I32Const(1), Instr::I32Add]);
+        assert_eq!(TrapKind::StackUnderflow, result.unwrap_err().kind);
+    }
+
+    #[test]
+    pub fn test_type_mismatch() {
+        let result = run(
+            vec![],
+            vec![],
+            vec![
+                Instr::I32Const(1),
+                Instr::RefNull(RefType::FuncRef),
+                Instr::I32Add,
+            ],
+        );
+        assert_eq!(TrapKind::TypeMismatch, result.unwrap_err().kind);
+    }
+
+    #[test]
+    pub fn test_loop() {
+        let result = run(
+            vec![ValType::I32],
+            vec![ValType::I32],
+            vec![
+                Instr::Block(
                     BlockType::Empty,
-                    vec![
-                        Instr::LocalGet(0),
-                        Instr::I32Const(1),
-                        Instr::I32Add,
-                        Instr::LocalTee(0),
-                        Instr::I32Const(3),
-                        Instr::I32Eq,
-                        Instr::If(BlockType::Empty, vec![Instr::Br(1)], vec![Instr::Br(0)]),
-                    ],
-                )],
+                    vec![Instr::Loop(
+                        BlockType::Empty,
+                        vec![
+                            Instr::LocalGet(0),
+                            Instr::I32Const(1),
+                            Instr::I32Add,
+                            Instr::LocalTee(0),
+                            Instr::I32Const(3),
+                            Instr::I32Eq,
+                            Instr::If(BlockType::Empty, vec![Instr::Br(2)], vec![Instr::Br(1)]),
+                        ],
+                    )],
+                ),
+                Instr::LocalGet(0),
+            ],
+        );
+        assert_eq!(Ok(vec![Val::I32(3)]), result);
+    }
+
+    /// A module computing factorials recursively, `fac` is function 0.
+    fn fac_store() -> Store {
+        let types = vec![FuncType {
+            params: vec![ValType::I64],
+            results: vec![ValType::I64],
+        }];
+        let fac = vec![
+            Instr::LocalGet(0),
+            Instr::I64Eqz,
+            Instr::If(
+                BlockType::ValTy(ValType::I64),
+                vec![Instr::I64Const(1)],
+                vec![
+                    Instr::LocalGet(0),
+                    Instr::LocalGet(0),
+                    Instr::I64Const(1),
+                    Instr::I64Sub,
+                    Instr::Call(0),
+                    Instr::I64Mul,
+                ],
             ),
+        ];
+        store_with_funcs(types, vec![(0, vec![], fac)])
+    }
+
+    #[test]
+    pub fn test_call_recursive() {
+        let mut store = fac_store();
+        let funcs = store.funcs.clone();
+        let result = VM::new(&funcs).invoke(&mut store, 0, vec![Val::I64(20)]);
+        assert_eq!(Ok(vec![Val::I64(2432902008176640000)]), result);
+    }
+
+    #[test]
+    pub fn test_call_locals() {
+        // callee locals are fresh and zeroed, the caller's are untouched
+        let types = vec![
+            FuncType {
+                params: vec![],
+                results: vec![ValType::I32, ValType::I32],
+            },
+            FuncType {
+                params: vec![ValType::I32],
+                results: vec![ValType::I32],
+            },
+        ];
+        let main = vec![
+            Instr::I32Const(7),
+            Instr::LocalSet(0),
+            Instr::I32Const(5),
+            Instr::Call(1),
+            Instr::LocalGet(0),
+        ];
+        let add_local = vec![
+            Instr::LocalGet(1),
+            Instr::I32Const(100),
+            Instr::I32Add,
+            Instr::LocalGet(0),
+            Instr::I32Add,
+        ];
+        let mut store = store_with_funcs(
+            types,
+            vec![
+                (0, vec![ValType::I32], main),
+                (1, vec![ValType::I32], add_local),
+            ],
+        );
+        let funcs = store.funcs.clone();
+        let result = VM::new(&funcs).invoke(&mut store, 0, vec![]);
+        assert_eq!(Ok(vec![Val::I32(105), Val::I32(7)]), result);
+    }
+
+    #[test]
+    pub fn test_return() {
+        // return discards the operands and labels below the results
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![
+                Instr::I32Const(1),
+                Instr::Block(
+                    BlockType::Empty,
+                    vec![Instr::I32Const(2), Instr::I32Const(3), Instr::Return],
+                ),
+                Instr::Unreachable,
+            ],
+        );
+        assert_eq!(Ok(vec![Val::I32(3)]), result);
+    }
+
+    #[test]
+    pub fn test_br_function_label() {
+        let result = run(
+            vec![ValType::I32],
+            vec![],
+            vec![Instr::I32Const(4), Instr::Br(0), Instr::Unreachable],
+        );
+        assert_eq!(Ok(vec![Val::I32(4)]), result);
+    }
+
+    #[test]
+    pub fn test_call_stack_exhausted() {
+        let ty = FuncType {
+            params: vec![],
+            results: vec![],
+        };
+        let mut store = store_with_funcs(vec![ty], vec![(0, vec![], vec![Instr::Call(0)])]);
+        let funcs = store.funcs.clone();
+        let mut vm = VM::new(&funcs);
+        store.max_call_depth = 100;
+        let result = vm.invoke(&mut store, 0, vec![]);
+        assert_eq!(
+            Err(Trap::new(TrapKind::StackExhausted, Some(0), vec![0])),
+            result
+        );
+        assert_eq!(100, vm.frames.len());
+    }
+
+    #[test]
+    pub fn test_call_indirect() {
+        let mut store = call_indirect_store();
+        let funcs = store.funcs.clone();
+        let mut dispatch = |i| VM::new(&funcs).invoke(&mut store, 0, vec![Val::I32(i)]);
+        // the callee's type is equal to the expected one, though declared at another index
+        assert_eq!(Ok(vec![Val::I32(20)]), dispatch(0));
+        let trap = |kind| Err(Trap::new(kind, Some(0), vec![2]));
+        assert_eq!(trap(TrapKind::IndirectCallTypeMismatch), dispatch(1));
+        assert_eq!(trap(TrapKind::UninitializedElement), dispatch(2));
+        assert_eq!(trap(TrapKind::UndefinedElement), dispatch(3));
+        assert_eq!(trap(TrapKind::UndefinedElement), dispatch(-1));
+    }
+
+    #[test]
+    pub fn test_global_stack_pointer() {
+        // prologue/epilogue of compiled code around a shadow stack frame
+        let ty = FuncType {
+            params: vec![],
+            results: vec![ValType::I32],
+        };
+        let body = vec![
+            Instr::GlobalGet(0),
+            Instr::I32Const(16),
+            Instr::I32Sub,
+            Instr::LocalTee(0),
+            Instr::GlobalSet(0),
+            Instr::GlobalGet(0),
             Instr::LocalGet(0),
-        ]);
-        assert_eq!(Some(Val::I32(2)), vm.result());
+            Instr::I32Const(16),
+            Instr::I32Add,
+            Instr::GlobalSet(0),
+        ];
+        let mut store = store_with_funcs(vec![ty], vec![(0, vec![ValType::I32], body)]);
+        store.globals.push(module::GlobalInst {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability: Mutability::Var,
+            },
+            value: Val::I32(1024),
+        });
+        store.modules[0].global_addrs.push(0);
+        let funcs = store.funcs.clone();
+        let result = VM::new(&funcs).invoke(&mut store, 0, vec![]);
+        assert_eq!(Ok(vec![Val::I32(1008)]), result);
+        assert_eq!(Val::I32(1024), module::global_read(&store, 0));
+    }
+
+    #[test]
+    pub fn test_host_call() {
+        let log = Rc::new(RefCell::new(vec![]));
+        let mut store = host_store(log.clone());
+        let funcs = store.funcs.clone();
+        let result = VM::new(&funcs).invoke(&mut store, 3, vec![]);
+        assert_eq!(Ok(vec![Val::I32(11)]), result);
+        assert_eq!(vec!["hello".to_string()], *log.borrow());
+        // the trap is reported at the call
+        let result = VM::new(&funcs).invoke(&mut store, 4, vec![]);
+        assert_eq!(
+            Err(Trap::new(
+                TrapKind::Host("boom".to_string()),
+                Some(4),
+                vec![1]
+            )),
+            result
+        );
+        // called directly, without a calling instance
+        let result = VM::new(&funcs).invoke(&mut store, 2, vec![]);
+        assert_eq!(
+            Err(Trap::new(
+                TrapKind::Host("boom".to_string()),
+                Some(2),
+                vec![]
+            )),
+            result
+        );
+    }
+
+    #[test]
+    pub fn test_loop_store() {
+        let mut store = loop_store();
+        let funcs = store.funcs.clone();
+        let mut run = |addr, n| VM::new(&funcs).invoke(&mut store, addr, vec![Val::I32(n)]);
+        assert_eq!(Ok(vec![Val::I64(4950)]), run(0, 100));
+        assert_eq!(Ok(vec![Val::I32(100)]), run(1, 100));
+        assert_eq!(Ok(vec![Val::I32(55)]), run(2, 10));
+
+        let mut run = |addr, n| VM::unchecked(&funcs).invoke(&mut store, addr, vec![Val::I32(n)]);
+        assert_eq!(Ok(vec![Val::I64(4950)]), run(0, 100));
+        assert_eq!(Ok(vec![Val::I32(100)]), run(1, 100));
+        assert_eq!(Ok(vec![Val::I32(55)]), run(2, 10));
+    }
+
+    /// Time the engines on `loop_store`, run with
+    /// `cargo test --release --lib bench_loops -- --ignored --nocapture`
+    ///
+    /// Measured in release builds, in ms for sum / nested / fib:
+    /// - vm with labels on a tagged operand stack, before they got a stack
+    ///   of their own: 393 / 477 / 133
+    /// - vm with its own label stack: ~180 / ~290 / ~60, vm-slots
+    ///   ~160 / ~250 / ~50
+    /// - cont with a boxed continuation: 603 / 875 / 213, kept in a Vec
+    ///   ~240 / ~400 / ~65
+    /// - flat: ~130 / ~115 / ~30
+    #[test]
+    #[ignore]
+    pub fn bench_loops() {
+        use std::time::Instant;
+        let store = loop_store();
+        let funcs = store.funcs.clone();
+        let cases = [
+            ("sum", 0, 1_000_000),
+            ("nested", 1, 1_000_000),
+            ("fib", 2, 25),
+        ];
+        type Engine<'a> = &'a dyn Fn(&mut Store, FuncAddr, Vec<Val>) -> Result<Vec<Val>, Trap>;
+        let engines: [(&str, Engine); 4] = [
+            ("vm", &|store, addr, args| {
+                VM::new(&funcs).invoke(store, addr, args)
+            }),
+            ("vm-slots", &|store, addr, args| {
+                VM::unchecked(&funcs).invoke(store, addr, args)
+            }),
+            ("cont", &|store, addr, args| {
+                cont::Instance::new(&funcs).invoke(store, addr, args)
+            }),
+            ("flat", &|store, addr, args| {
+                flat::FlatVM::new(&funcs).invoke(store, addr, args)
+            }),
+        ];
+        for (name, addr, n) in cases {
+            for (engine, invoke) in &engines {
+                let mut store = store.clone();
+                let start = Instant::now();
+                invoke(&mut store, addr, vec![Val::I32(n)]).unwrap();
+                println!("{:>6} {:>8}: {:?}", name, engine, start.elapsed());
+            }
+        }
     }
 }
@@ -0,0 +1,145 @@
+//! Host access to guest memory.
+
+use crate::module::MemInst;
+use crate::trap::TrapKind;
+
+/// A primitive stored in memory as little-endian bytes.
+pub trait LeBytes: Sized {
+    const SIZE: usize;
+    fn from_le(bytes: &[u8]) -> Self;
+    fn write_le(self, bytes: &mut [u8]);
+}
+
+macro_rules! le_bytes {
+    ($($ty:ty)*) => {
+        $(
+            impl LeBytes for $ty {
+                const SIZE: usize = std::mem::size_of::<$ty>();
+
+                fn from_le(bytes: &[u8]) -> Self {
+                    <$ty>::from_le_bytes(bytes.try_into().unwrap())
+                }
+
+                fn write_le(self, bytes: &mut [u8]) {
+                    bytes.copy_from_slice(&self.to_le_bytes());
+                }
+            }
+        )*
+    };
+}
+
+le_bytes!(u8 i8 u16 i16 u32 i32 u64 i64 f32 f64);
+
+/// Bounds-checked view of a memory instance. The view borrows the memory
+/// mutably, so a host function has to drop it before anything else can touch
+/// the store, and the memory can't grow from under it.
+pub struct MemoryView<'a> {
+    data: &'a mut [u8],
+}
+
+impl<'a> MemoryView<'a> {
+    pub fn new(mem: &'a mut MemInst) -> Self {
+        MemoryView {
+            data: &mut mem.data,
+        }
+    }
+
+    /// Size in bytes
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    fn range(&self, ptr: u32, len: u32) -> Result<std::ops::Range<usize>, TrapKind> {
+        let end = ptr as u64 + len as u64;
+        if end > self.data.len() as u64 {
+            return Err(TrapKind::MemoryOutOfBounds);
+        }
+        Ok(ptr as usize..end as usize)
+    }
+
+    pub fn bytes(&self, ptr: u32, len: u32) -> Result<&[u8], TrapKind> {
+        let range = self.range(ptr, len)?;
+        Ok(&self.data[range])
+    }
+
+    pub fn bytes_mut(&mut self, ptr: u32, len: u32) -> Result<&mut [u8], TrapKind> {
+        let range = self.range(ptr, len)?;
+        Ok(&mut self.data[range])
+    }
+
+    pub fn write_bytes(&mut self, ptr: u32, bytes: &[u8]) -> Result<(), TrapKind> {
+        let len = u32::try_from(bytes.len()).map_err(|_| TrapKind::MemoryOutOfBounds)?;
+        self.bytes_mut(ptr, len)?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// The UTF-8 string of `len` bytes at `ptr`
+    pub fn str(&self, ptr: u32, len: u32) -> Result<&str, TrapKind> {
+        std::str::from_utf8(self.bytes(ptr, len)?)
+            .map_err(|e| TrapKind::Host(format!("invalid utf-8 string at {}: {}", ptr, e)))
+    }
+
+    pub fn read<T: LeBytes>(&self, ptr: u32) -> Result<T, TrapKind> {
+        Ok(T::from_le(self.bytes(ptr, T::SIZE as u32)?))
+    }
+
+    pub fn write<T: LeBytes>(&mut self, ptr: u32, val: T) -> Result<(), TrapKind> {
+        val.write_le(self.bytes_mut(ptr, T::SIZE as u32)?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Limits, MemType};
+
+    fn mem(data: Vec<u8>) -> MemInst {
+        MemInst {
+            mem_type: MemType {
+                limits: Limits { min: 0, max: None },
+            },
+            data,
+        }
+    }
+
+    #[test]
+    fn test_primitives() {
+        let mut mem = mem(vec![0; 16]);
+        let mut view = MemoryView::new(&mut mem);
+        view.write(0, 0x0102_0304u32).unwrap();
+        view.write(4, -2i16).unwrap();
+        view.write(8, 1.5f64).unwrap();
+        assert_eq!(Ok(&[4, 3, 2, 1, 0xfe, 0xff][..]), view.bytes(0, 6));
+        assert_eq!(Ok(0x0304u16), view.read(0));
+        assert_eq!(Ok(-2i16), view.read(4));
+        assert_eq!(Ok(1.5f64), view.read(8));
+        // straddling the end
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), view.read::<u64>(9));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), view.write(15, 0u16));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), view.read::<u8>(u32::MAX));
+        assert_eq!(Ok(0x3fu8), view.read(15));
+    }
+
+    #[test]
+    fn test_strings() {
+        let mut mem = mem(vec![0; 8]);
+        let mut view = MemoryView::new(&mut mem);
+        view.write_bytes(2, "héllo".as_bytes()).unwrap();
+        assert_eq!(Ok("héllo"), view.str(2, 6));
+        assert_eq!(Ok(""), view.str(8, 0));
+        assert_eq!(Err(TrapKind::MemoryOutOfBounds), view.str(4, 5));
+        // splitting the two bytes of é
+        assert!(matches!(view.str(2, 2), Err(TrapKind::Host(_))));
+        assert_eq!(
+            Err(TrapKind::MemoryOutOfBounds),
+            view.write_bytes(4, &[0; 5])
+        );
+        view.bytes_mut(0, 2).unwrap().fill(7);
+        assert_eq!(Ok(&[7, 7, b'h'][..]), view.bytes(0, 3));
+    }
+}
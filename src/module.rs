@@ -1,3 +1,9 @@
+use std::fmt;
+use std::rc::Rc;
+
+use crate::exec::exec_plain;
+use crate::memory::MemoryView;
+use crate::trap::{Trap, TrapKind};
 use crate::types::*;
 
 // ============================================================================
@@ -235,23 +241,46 @@ pub type DataAddr = u32;
 // Store holds all runtime instances
 #[derive(Debug, Clone)]
 pub struct Store {
-    pub funcs: Vec<FuncInst>,
+    /// Shared so interpreters can hold on to it while the store changes
+    pub funcs: Rc<Vec<FuncInst>>,
     pub tables: Vec<TableInst>,
     pub mems: Vec<MemInst>,
     pub globals: Vec<GlobalInst>,
     pub elems: Vec<ElemInst>,
     pub datas: Vec<DataInst>,
+    pub modules: Vec<ModuleInst>,
+    /// Cap on the size of any memory in pages, imposed by the embedder on top
+    /// of the memories' own limits
+    pub max_mem_pages: Option<u32>,
+    /// Nesting of calls allowed before trapping with `StackExhausted`
+    pub max_call_depth: usize,
+    /// Run `func_invoke` on every engine and panic if they disagree
+    pub cross_check: bool,
+    /// Frames of the invocations suspended in host functions, which count
+    /// towards the call depth of the ones they make
+    pub(crate) call_depth: usize,
+    /// Host functions and `exec::call_unseen` calls running
+    pub(crate) host_depth: usize,
+    /// Lowered bodies of the local functions called so far, by `FuncAddr`
+    pub(crate) compiled: Vec<Option<Rc<crate::flat::Compiled>>>,
 }
 
 impl Store {
     pub fn new() -> Self {
         Store {
-            funcs: vec![],
+            funcs: Rc::default(),
             tables: vec![],
             mems: vec![],
             globals: vec![],
             elems: vec![],
             datas: vec![],
+            modules: vec![],
+            max_mem_pages: None,
+            max_call_depth: crate::exec::DEFAULT_MAX_CALL_DEPTH,
+            cross_check: false,
+            call_depth: 0,
+            host_depth: 0,
+            compiled: vec![],
         }
     }
 }
@@ -268,7 +297,8 @@ pub enum FuncInst {
     Local {
         func_type: FuncType,
         module: ModuleAddr,
-        code: Code,
+        // shared so interpreters can hold on to a body while the store changes
+        code: Rc<Code>,
     },
     Host {
         func_type: FuncType,
@@ -278,11 +308,115 @@ pub enum FuncInst {
 
 pub type ModuleAddr = u32;
 
-#[derive(Debug, Clone)]
+/// Signature of the closures behind host functions: args match the
+/// function's param types, results must match its result types.
+pub type HostFn = dyn Fn(&mut Caller, &[Val]) -> Result<Vec<Val>, TrapKind>;
+
+#[derive(Clone)]
 pub struct HostFunc {
-    // Placeholder for host function pointer/callback
-    // In real implementation, this would be a function pointer or closure
+    /// Used in messages only
     pub name: String,
+    func: Rc<HostFn>,
+}
+
+impl HostFunc {
+    pub fn new(
+        name: &str,
+        func: impl Fn(&mut Caller, &[Val]) -> Result<Vec<Val>, TrapKind> + 'static,
+    ) -> Self {
+        HostFunc {
+            name: name.to_string(),
+            func: Rc::new(func),
+        }
+    }
+}
+
+impl fmt::Debug for HostFunc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HostFunc({})", self.name)
+    }
+}
+
+/// What a host function sees of its caller.
+pub struct Caller<'a> {
+    pub store: &'a mut Store,
+    /// Instance of the calling function, `None` when called by the embedder
+    pub module: Option<ModuleAddr>,
+}
+
+impl Caller<'_> {
+    /// Export `name` of the calling instance
+    pub fn get_export(&self, name: &str) -> Option<ExternVal> {
+        self.store.modules[self.module? as usize].get_export(name)
+    }
+
+    /// Exported memory `name` of the calling instance
+    pub fn memory(&mut self, name: &str) -> Option<&mut MemInst> {
+        match self.get_export(name)? {
+            ExternVal::Memory(addr) => Some(&mut self.store.mems[addr as usize]),
+            _ => None,
+        }
+    }
+
+    /// Bounds-checked view of exported memory `name` of the calling instance
+    pub fn memory_view(&mut self, name: &str) -> Option<MemoryView<'_>> {
+        self.memory(name).map(MemoryView::new)
+    }
+
+    /// Value of exported global `name` of the calling instance
+    pub fn global(&self, name: &str) -> Option<Val> {
+        match self.get_export(name)? {
+            ExternVal::Global(addr) => Some(global_read(self.store, addr)),
+            _ => None,
+        }
+    }
+
+    /// Set exported global `name` of the calling instance
+    pub fn set_global(&mut self, name: &str, val: Val) -> Result<(), String> {
+        match self.get_export(name) {
+            Some(ExternVal::Global(addr)) => global_write(self.store, addr, val),
+            _ => Err(format!("no exported global {}", name)),
+        }
+    }
+}
+
+/// Call host function `addr` on behalf of module instance `module`, `depth`
+/// frames deep counting its own.
+pub fn host_call(
+    store: &mut Store,
+    addr: FuncAddr,
+    module: Option<ModuleAddr>,
+    depth: usize,
+    args: &[Val],
+) -> Result<Vec<Val>, TrapKind> {
+    let FuncInst::Host {
+        func_type,
+        host_func,
+    } = &store.funcs[addr as usize]
+    else {
+        unreachable!("not a host function: {}", addr);
+    };
+    let results_type = func_type.results.clone();
+    if store.host_depth >= crate::exec::MAX_HOST_NESTING {
+        return Err(TrapKind::StackExhausted);
+    }
+    let func = host_func.func.clone();
+    let outer = std::mem::replace(&mut store.call_depth, depth);
+    store.host_depth += 1;
+    let results = func(&mut Caller { store, module }, args);
+    store.host_depth -= 1;
+    store.call_depth = outer;
+    let results = results?;
+    // the host is trusted no more than unvalidated code
+    if results.len() != results_type.len()
+        || results
+            .iter()
+            .zip(&results_type)
+            .any(|(v, ty)| v.val_type() != *ty)
+    {
+        return Err(TrapKind::TypeMismatch);
+    }
+    Ok(results)
 }
 
 // Table Instance
@@ -299,6 +433,26 @@ pub enum Ref {
     Extern(u32), // External reference (opaque)
 }
 
+impl Ref {
+    pub fn ref_type(&self) -> RefType {
+        match self {
+            Ref::Null(ty) => ty.clone(),
+            Ref::Func(_) => RefType::FuncRef,
+            Ref::Extern(_) => RefType::ExternRef,
+        }
+    }
+
+    pub fn is_null(&self) -> bool {
+        matches!(self, Ref::Null(_))
+    }
+}
+
+/// Size of a memory page in bytes
+pub const PAGE_SIZE: usize = 65536;
+
+/// Largest size of a memory in pages, 4 GiB
+pub const MAX_PAGES: u32 = 65536;
+
 // Memory Instance
 #[derive(Debug, Clone)]
 pub struct MemInst {
@@ -327,7 +481,7 @@ pub struct DataInst {
 }
 
 // Module Instance (runtime representation)
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct ModuleInst {
     pub types: Vec<FuncType>,
     pub func_addrs: Vec<FuncAddr>,
@@ -339,6 +493,15 @@ pub struct ModuleInst {
     pub exports: Vec<ExportInst>,
 }
 
+impl ModuleInst {
+    pub fn get_export(&self, name: &str) -> Option<ExternVal> {
+        self.exports
+            .iter()
+            .find(|export| export.name == name)
+            .map(|export| export.value.clone())
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ExportInst {
     pub name: String,
@@ -363,32 +526,310 @@ pub fn module_decode(_bytes: &[u8]) -> Result<Module, String> {
     todo!("module_decode not yet implemented")
 }
 
-/// Instantiate a module with given imports
+/// Instantiate a module with given imports. The instance is added to
+/// `store.modules`, and a copy returned.
 pub fn module_instantiate(
-    _store: &mut Store,
-    _module: &Module,
-    _imports: &[ExternVal],
+    store: &mut Store,
+    module: &Module,
+    imports: &[ExternVal],
 ) -> Result<ModuleInst, String> {
-    // TODO: implement instantiation
-    // - Allocate functions, tables, memories, globals
-    // - Initialize tables with element segments
-    // - Initialize memories with data segments
-    // - Build export map
-    // - Execute start function if present
-    todo!("module_instantiate not yet implemented")
+    if imports.len() != module.imports.len() {
+        return Err(format!(
+            "expected {} imports, got {}",
+            module.imports.len(),
+            imports.len()
+        ));
+    }
+    for ((module_name, name, expected), val) in module.module_imports().iter().zip(imports) {
+        if !extern_type_matches(&extern_type(store, val), expected) {
+            return Err(format!(
+                "incompatible import type for {}.{}",
+                module_name, name
+            ));
+        }
+    }
+
+    // the instance is reachable by its address while being built, so that
+    // constant expressions can refer to its imports and functions
+    let addr = store.modules.len() as ModuleAddr;
+    let mut inst = ModuleInst {
+        types: module.types.clone(),
+        ..Default::default()
+    };
+    for val in imports {
+        match *val {
+            ExternVal::Func(a) => inst.func_addrs.push(a),
+            ExternVal::Table(a) => inst.table_addrs.push(a),
+            ExternVal::Memory(a) => inst.mem_addrs.push(a),
+            ExternVal::Global(a) => inst.global_addrs.push(a),
+        }
+    }
+    for (type_idx, code) in module.functions.iter().zip(&module.code) {
+        let func_type = module.types[*type_idx as usize].clone();
+        inst.func_addrs
+            .push(func_alloc(store, func_type, addr, code.clone()));
+    }
+    // tables and memories start empty and grow to their minimum, checked
+    // against the same limits as `table.grow` and `memory.grow`
+    for table in &module.tables {
+        let table_addr = store.tables.len() as TableAddr;
+        inst.table_addrs.push(table_addr);
+        let mut table_type = table.table_type.clone();
+        let min = std::mem::take(&mut table_type.limits.min);
+        let init = Ref::Null(table_type.elem_type.clone());
+        store.tables.push(TableInst {
+            table_type,
+            elem: vec![],
+        });
+        table_grow(store, table_addr, min, init)?;
+    }
+    for memory in &module.memories {
+        let mem_addr = store.mems.len() as MemAddr;
+        inst.mem_addrs.push(mem_addr);
+        let mut mem_type = memory.mem_type.clone();
+        let min = std::mem::take(&mut mem_type.limits.min);
+        store.mems.push(MemInst {
+            mem_type,
+            data: vec![],
+        });
+        mem_grow(store, mem_addr, min)?;
+    }
+    store.modules.push(inst);
+
+    // globals see only the imported ones
+    let mut global_vals = vec![];
+    for global in &module.globals {
+        global_vals.push(eval_const(store, addr, &global.init_expr)?);
+    }
+    for (global, value) in module.globals.iter().zip(global_vals) {
+        let global_addr = store.globals.len() as GlobalAddr;
+        store.modules[addr as usize].global_addrs.push(global_addr);
+        store.globals.push(GlobalInst {
+            global_type: global.global_type.clone(),
+            value,
+        });
+    }
+    for element in &module.elements {
+        let mut elem = vec![];
+        for expr in &element.init {
+            match eval_const(store, addr, expr)? {
+                Val::Ref(r) => elem.push(r),
+                v => return Err(format!("element initializer is not a reference: {:?}", v)),
+            }
+        }
+        let elem_addr = store.elems.len() as ElemAddr;
+        store.modules[addr as usize].elem_addrs.push(elem_addr);
+        store.elems.push(ElemInst {
+            elem_type: element.elem_type.clone(),
+            elem,
+        });
+    }
+    for data in &module.data {
+        let data_addr = store.datas.len() as DataAddr;
+        store.modules[addr as usize].data_addrs.push(data_addr);
+        store.datas.push(DataInst {
+            data: data.init.clone(),
+        });
+    }
+    store.modules[addr as usize].exports = module
+        .exports
+        .iter()
+        .map(|export| {
+            let inst = &store.modules[addr as usize];
+            let value = match export.desc {
+                ExportDesc::Func(i) => {
+                    ExternVal::Func(lookup(&inst.func_addrs, i as usize, "function")?)
+                }
+                ExportDesc::Table(i) => {
+                    ExternVal::Table(lookup(&inst.table_addrs, i as usize, "table")?)
+                }
+                ExportDesc::Memory(i) => {
+                    ExternVal::Memory(lookup(&inst.mem_addrs, i as usize, "memory")?)
+                }
+                ExportDesc::Global(i) => {
+                    ExternVal::Global(lookup(&inst.global_addrs, i as usize, "global")?)
+                }
+            };
+            Ok(ExportInst {
+                name: export.name.clone(),
+                value,
+            })
+        })
+        .collect::<Result<_, String>>()?;
+
+    // segments are applied in order, a trap leaves the earlier writes in place
+    for (i, element) in module.elements.iter().enumerate() {
+        match &element.mode {
+            ElemMode::Active { table, offset } => {
+                lookup(
+                    &store.modules[addr as usize].table_addrs,
+                    *table as usize,
+                    "table",
+                )?;
+                let n = element.init.len() as i32;
+                let init = [
+                    Instr::I32Const(0),
+                    Instr::I32Const(n),
+                    Instr::TableInit(*table as usize, i),
+                    Instr::ElemDrop(i),
+                ];
+                let mut vs = vec![eval_const(store, addr, offset)?];
+                for instr in &init {
+                    exec_plain(instr, store, addr, &mut vs).map_err(|e| e.to_string())?;
+                }
+            }
+            ElemMode::Declarative => {
+                exec_plain(&Instr::ElemDrop(i), store, addr, &mut Vec::<Val>::new())
+                    .map_err(|e| e.to_string())?;
+            }
+            ElemMode::Passive => {}
+        }
+    }
+    for (i, data) in module.data.iter().enumerate() {
+        if let DataMode::Active { memory, offset } = &data.mode {
+            // only memory 0 exists before multi-memory
+            let mems = &store.modules[addr as usize].mem_addrs;
+            lookup(&mems[..mems.len().min(1)], *memory as usize, "memory")?;
+            let n = data.init.len() as i32;
+            let init = [
+                Instr::I32Const(0),
+                Instr::I32Const(n),
+                Instr::MemoryInit(i),
+                Instr::DataDrop(i),
+            ];
+            let mut vs = vec![eval_const(store, addr, offset)?];
+            for instr in &init {
+                exec_plain(instr, store, addr, &mut vs).map_err(|e| e.to_string())?;
+            }
+        }
+    }
+
+    if let Some(start) = &module.start {
+        let func_addr = lookup(
+            &store.modules[addr as usize].func_addrs,
+            start.func_idx as usize,
+            "function",
+        )?;
+        func_invoke(store, func_addr, &[])?;
+    }
+    Ok(store.modules[addr as usize].clone())
+}
+
+/// Evaluate a constant expression on behalf of module instance `module`.
+fn eval_const(store: &mut Store, module: ModuleAddr, expr: &Expr) -> Result<Val, String> {
+    let mut vs = vec![];
+    for instr in &expr.instrs {
+        let inst = &store.modules[module as usize];
+        match *instr {
+            Instr::GlobalGet(x) => {
+                lookup(&inst.global_addrs, x, "global")?;
+            }
+            Instr::RefFunc(x) => {
+                lookup(&inst.func_addrs, x, "function")?;
+            }
+            _ => {}
+        }
+        exec_plain(instr, store, module, &mut vs).map_err(|e| e.to_string())?;
+    }
+    vs.pop()
+        .ok_or_else(|| "constant expression has no value".to_string())
+}
+
+/// Address of item `idx` among the `what`s of an instance being built. Only
+/// validation would rule out unknown ones.
+fn lookup(addrs: &[u32], idx: usize, what: &str) -> Result<u32, String> {
+    addrs
+        .get(idx)
+        .copied()
+        .ok_or_else(|| format!("unknown {} {}", what, idx))
+}
+
+/// Type of an external value
+pub fn extern_type(store: &Store, val: &ExternVal) -> ExternType {
+    match *val {
+        ExternVal::Func(a) => ExternType::Func(func_type(store, a).clone()),
+        ExternVal::Table(a) => ExternType::Table(store.tables[a as usize].table_type.clone()),
+        ExternVal::Memory(a) => ExternType::Memory(store.mems[a as usize].mem_type.clone()),
+        ExternVal::Global(a) => ExternType::Global(store.globals[a as usize].global_type.clone()),
+    }
+}
+
+/// Whether a value of type `actual` can be imported as `expected`
+pub fn extern_type_matches(actual: &ExternType, expected: &ExternType) -> bool {
+    fn limits_match(actual: &Limits, expected: &Limits) -> bool {
+        actual.min >= expected.min
+            && expected
+                .max
+                .is_none_or(|max| actual.max.is_some_and(|a| a <= max))
+    }
+    match (actual, expected) {
+        (ExternType::Func(a), ExternType::Func(e)) => a == e,
+        (ExternType::Table(a), ExternType::Table(e)) => {
+            a.elem_type == e.elem_type && limits_match(&a.limits, &e.limits)
+        }
+        (ExternType::Memory(a), ExternType::Memory(e)) => limits_match(&a.limits, &e.limits),
+        (ExternType::Global(a), ExternType::Global(e)) => a == e,
+        _ => false,
+    }
 }
 
 /// Invoke a function with given arguments
 pub fn func_invoke(
-    _store: &mut Store,
-    _func_addr: FuncAddr,
-    _args: &[Val],
+    store: &mut Store,
+    func_addr: FuncAddr,
+    args: &[Val],
+) -> Result<Vec<Val>, String> {
+    let func_type = match store.funcs.get(func_addr as usize) {
+        Some(FuncInst::Local { func_type, .. } | FuncInst::Host { func_type, .. }) => func_type,
+        None => return Err(format!("unknown function {}", func_addr)),
+    };
+    if args.len() != func_type.params.len() {
+        return Err(format!(
+            "expected {} arguments, got {}",
+            func_type.params.len(),
+            args.len()
+        ));
+    }
+    for (i, (arg, param)) in args.iter().zip(&func_type.params).enumerate() {
+        if arg.val_type() != *param {
+            return Err(format!(
+                "argument {} has type {:?}, expected {:?}",
+                i,
+                arg.val_type(),
+                param
+            ));
+        }
+    }
+    func_invoke_unchecked(store, func_addr, args)
+}
+
+/// Invoke a function with arguments already checked against its type
+pub(crate) fn func_invoke_unchecked(
+    store: &mut Store,
+    func_addr: FuncAddr,
+    args: &[Val],
 ) -> Result<Vec<Val>, String> {
-    // TODO: implement function invocation
-    // - Set up stack frame
-    // - Execute instructions
-    // - Return results
-    todo!("func_invoke not yet implemented")
+    match &store.funcs[func_addr as usize] {
+        FuncInst::Local { .. } if store.cross_check => {
+            crate::diff::diff_invoke(store, func_addr, args.to_vec())
+                .map_err(|trap| trap.to_string())
+        }
+        FuncInst::Local { .. } => {
+            let funcs = store.funcs.clone();
+            crate::flat::FlatVM::new(&funcs)
+                .invoke(store, func_addr, args.to_vec())
+                .map_err(|trap| trap.to_string())
+        }
+        FuncInst::Host { .. } => {
+            if store.call_depth >= store.max_call_depth {
+                let trap = Trap::new(TrapKind::StackExhausted, Some(func_addr), vec![]);
+                return Err(trap.to_string());
+            }
+            let depth = store.call_depth + 1;
+            host_call(store, func_addr, None, depth, args)
+                .map_err(|kind| Trap::new(kind, Some(func_addr), vec![]).to_string())
+        }
+    }
 }
 
 /// Get function type
@@ -399,10 +840,26 @@ pub fn func_type(store: &Store, func_addr: FuncAddr) -> &FuncType {
     }
 }
 
+/// Allocate a function defined by module instance `module`
+pub fn func_alloc(
+    store: &mut Store,
+    func_type: FuncType,
+    module: ModuleAddr,
+    code: Code,
+) -> FuncAddr {
+    let addr = store.funcs.len() as u32;
+    Rc::make_mut(&mut store.funcs).push(FuncInst::Local {
+        func_type,
+        module,
+        code: Rc::new(code),
+    });
+    addr
+}
+
 /// Allocate a host function
 pub fn func_alloc_host(store: &mut Store, func_type: FuncType, host_func: HostFunc) -> FuncAddr {
     let addr = store.funcs.len() as u32;
-    store.funcs.push(FuncInst::Host {
+    Rc::make_mut(&mut store.funcs).push(FuncInst::Host {
         func_type,
         host_func,
     });
@@ -440,15 +897,26 @@ pub fn table_size(store: &Store, table_addr: TableAddr) -> u32 {
     store.tables[table_addr as usize].elem.len() as u32
 }
 
-/// Grow table
+/// Grow table by delta elements set to `init`, returning the previous size
 pub fn table_grow(
-    _store: &mut Store,
-    _table_addr: TableAddr,
-    _delta: u32,
-    _init: Ref,
+    store: &mut Store,
+    table_addr: TableAddr,
+    delta: u32,
+    init: Ref,
 ) -> Result<u32, String> {
-    // TODO: implement table grow with limit checking
-    todo!("table_grow not yet implemented")
+    let table = &mut store.tables[table_addr as usize];
+    let old = table.elem.len() as u32;
+    let new = old
+        .checked_add(delta)
+        .filter(|&n| table.table_type.limits.max.is_none_or(|max| n <= max))
+        .ok_or_else(|| "table size limit exceeded".to_string())?;
+    table
+        .elem
+        .try_reserve_exact(delta as usize)
+        .map_err(|e| e.to_string())?;
+    table.elem.resize(new as usize, init);
+    table.table_type.limits.min = new;
+    Ok(old)
 }
 
 /// Read from memory
@@ -479,16 +947,35 @@ pub fn mem_write(
     }
 }
 
+/// Bounds-checked view of memory
+pub fn mem_view(store: &mut Store, mem_addr: MemAddr) -> MemoryView<'_> {
+    MemoryView::new(&mut store.mems[mem_addr as usize])
+}
+
 /// Get memory size in pages (64KiB each)
 pub fn mem_size(store: &Store, mem_addr: MemAddr) -> u32 {
     let mem = &store.mems[mem_addr as usize];
-    (mem.data.len() / 65536) as u32
+    (mem.data.len() / PAGE_SIZE) as u32
 }
 
-/// Grow memory by delta pages
-pub fn mem_grow(_store: &mut Store, _mem_addr: MemAddr, _delta: u32) -> Result<u32, String> {
-    // TODO: implement memory grow with limit checking
-    todo!("mem_grow not yet implemented")
+/// Grow memory by delta pages, returning the previous size
+pub fn mem_grow(store: &mut Store, mem_addr: MemAddr, delta: u32) -> Result<u32, String> {
+    let old = mem_size(store, mem_addr);
+    let cap = store.max_mem_pages;
+    let mem = &mut store.mems[mem_addr as usize];
+    let new = old
+        .checked_add(delta)
+        .filter(|&n| n <= MAX_PAGES)
+        .filter(|&n| mem.mem_type.limits.max.is_none_or(|max| n <= max))
+        .filter(|&n| cap.is_none_or(|cap| n <= cap))
+        .ok_or_else(|| "memory size limit exceeded".to_string())?;
+    let len = new as usize * PAGE_SIZE;
+    mem.data
+        .try_reserve_exact(len - mem.data.len())
+        .map_err(|e| e.to_string())?;
+    mem.data.resize(len, 0);
+    mem.mem_type.limits.min = new;
+    Ok(old)
 }
 
 /// Read global value
@@ -506,3 +993,1018 @@ pub fn global_write(store: &mut Store, global_addr: GlobalAddr, val: Val) -> Res
         Err("global is immutable".to_string())
     }
 }
+
+#[cfg(test)]
+pub(crate) mod testing {
+    use super::*;
+
+    /// Build a store holding a single module instance with the given types,
+    /// whose functions are given as (type index, declared locals, body).
+    pub fn store_with_funcs(
+        types: Vec<FuncType>,
+        funcs: Vec<(usize, Vec<ValType>, Vec<Instr>)>,
+    ) -> Store {
+        let mut store = Store::new();
+        let mut inst = ModuleInst {
+            types: types.clone(),
+            ..Default::default()
+        };
+        for (ty, locals, instrs) in funcs {
+            let code = Code {
+                locals,
+                body: Expr { instrs },
+            };
+            inst.func_addrs
+                .push(func_alloc(&mut store, types[ty].clone(), 0, code));
+        }
+        store.modules.push(inst);
+        store
+    }
+
+    /// Give module instance 0 a funcref table holding `elem`.
+    pub fn with_table(store: &mut Store, elem: Vec<Ref>) {
+        let table_type = TableType {
+            limits: Limits {
+                min: elem.len() as u32,
+                max: None,
+            },
+            elem_type: RefType::FuncRef,
+        };
+        store.modules[0].table_addrs.push(store.tables.len() as u32);
+        store.tables.push(TableInst { table_type, elem });
+    }
+
+    /// A module whose function 0 `[i32] -> [i32]` applies the function at the
+    /// given index of its table to 10, using a type declared twice.
+    pub fn call_indirect_store() -> Store {
+        let unop = FuncType {
+            params: vec![ValType::I32],
+            results: vec![ValType::I32],
+        };
+        let konst = FuncType {
+            params: vec![],
+            results: vec![ValType::I32],
+        };
+        let dispatch = vec![
+            Instr::I32Const(10),
+            Instr::LocalGet(0),
+            Instr::CallIndirect(0, 2),
+        ];
+        let double = vec![Instr::LocalGet(0), Instr::LocalGet(0), Instr::I32Add];
+        let mut store = store_with_funcs(
+            vec![unop.clone(), konst, unop],
+            vec![
+                (0, vec![], dispatch),
+                (0, vec![], double),
+                (1, vec![], vec![Instr::I32Const(42)]),
+            ],
+        );
+        with_table(
+            &mut store,
+            vec![Ref::Func(1), Ref::Func(2), Ref::Null(RefType::FuncRef)],
+        );
+        store
+    }
+
+    /// Control flow cases from `br.wast`, `br_if.wast`, `br_table.wast` and
+    /// `select.wast`, run by each interpreter as a function `[] -> results`
+    /// with an i32 local: (name, results, body, expected).
+    #[allow(clippy::type_complexity)]
+    pub fn control_cases() -> Vec<(&'static str, Vec<ValType>, Vec<Instr>, Vec<Val>)> {
+        use Instr::*;
+        let i32_block = |body| Block(BlockType::ValTy(ValType::I32), body);
+        let br_table = |i| {
+            vec![i32_block(vec![
+                Block(
+                    BlockType::Empty,
+                    vec![
+                        Block(
+                            BlockType::Empty,
+                            vec![I32Const(12), I32Const(i), BrTable(vec![0, 1], 2)],
+                        ),
+                        I32Const(10),
+                        Br(1),
+                    ],
+                ),
+                I32Const(11),
+            ])]
+        };
+        let select = |c| vec![I32Const(1), I32Const(2), I32Const(c), Select(None)];
+        vec![
+            (
+                "br_if taken",
+                vec![ValType::I32],
+                vec![i32_block(vec![
+                    I32Const(1),
+                    I32Const(1),
+                    BrIf(0),
+                    Drop,
+                    I32Const(2),
+                ])],
+                vec![Val::I32(1)],
+            ),
+            (
+                "br_if not taken",
+                vec![ValType::I32],
+                vec![i32_block(vec![
+                    I32Const(1),
+                    I32Const(0),
+                    BrIf(0),
+                    Drop,
+                    I32Const(2),
+                ])],
+                vec![Val::I32(2)],
+            ),
+            (
+                "br_if loop",
+                vec![ValType::I32],
+                vec![
+                    Loop(
+                        BlockType::Empty,
+                        vec![
+                            LocalGet(0),
+                            I32Const(1),
+                            I32Add,
+                            LocalTee(0),
+                            I32Const(5),
+                            I32LtS,
+                            BrIf(0),
+                        ],
+                    ),
+                    LocalGet(0),
+                ],
+                vec![Val::I32(5)],
+            ),
+            (
+                "br_table 0",
+                vec![ValType::I32],
+                br_table(0),
+                vec![Val::I32(10)],
+            ),
+            (
+                "br_table 1",
+                vec![ValType::I32],
+                br_table(1),
+                vec![Val::I32(11)],
+            ),
+            (
+                "br_table 2",
+                vec![ValType::I32],
+                br_table(2),
+                vec![Val::I32(12)],
+            ),
+            (
+                "br_table default",
+                vec![ValType::I32],
+                br_table(5),
+                vec![Val::I32(12)],
+            ),
+            (
+                "br_table default -1",
+                vec![ValType::I32],
+                br_table(-1),
+                vec![Val::I32(12)],
+            ),
+            (
+                "br outer with value",
+                vec![ValType::I32],
+                vec![i32_block(vec![
+                    i32_block(vec![I32Const(3), I32Const(4), Br(1)]),
+                    I32Const(5),
+                    I32Add,
+                ])],
+                vec![Val::I32(4)],
+            ),
+            (
+                "br skips rest",
+                vec![ValType::I32],
+                vec![
+                    Block(BlockType::Empty, vec![Br(0), Unreachable]),
+                    I32Const(1),
+                ],
+                vec![Val::I32(1)],
+            ),
+            (
+                "labels of ended blocks",
+                vec![ValType::I32],
+                vec![
+                    i32_block(vec![
+                        Block(BlockType::Empty, vec![Nop]),
+                        Block(BlockType::Empty, vec![]),
+                        I32Const(0),
+                        If(BlockType::Empty, vec![], vec![]),
+                        I32Const(7),
+                        Br(0),
+                    ]),
+                    I32Const(1),
+                    I32Add,
+                ],
+                vec![Val::I32(8)],
+            ),
+            (
+                "if in nested else",
+                vec![ValType::I32],
+                vec![
+                    I32Const(0),
+                    If(
+                        BlockType::ValTy(ValType::I32),
+                        vec![I32Const(1)],
+                        vec![
+                            I32Const(1),
+                            If(
+                                BlockType::ValTy(ValType::I32),
+                                vec![I32Const(2), I32Const(3), Br(1)],
+                                vec![I32Const(4)],
+                            ),
+                        ],
+                    ),
+                ],
+                vec![Val::I32(3)],
+            ),
+            (
+                "select first",
+                vec![ValType::I32],
+                select(1),
+                vec![Val::I32(1)],
+            ),
+            (
+                "select second",
+                vec![ValType::I32],
+                select(0),
+                vec![Val::I32(2)],
+            ),
+            (
+                "select typed",
+                vec![ValType::FuncRef],
+                vec![
+                    RefNull(RefType::FuncRef),
+                    RefNull(RefType::FuncRef),
+                    I32Const(1),
+                    Select(Some(ValType::FuncRef)),
+                ],
+                vec![Val::Ref(Ref::Null(RefType::FuncRef))],
+            ),
+            (
+                "drop",
+                vec![ValType::I32],
+                vec![I32Const(1), I32Const(2), Drop],
+                vec![Val::I32(1)],
+            ),
+        ]
+    }
+
+    /// Types for `multi_value_cases`, function type 0 is `[] -> [i32 i32]`.
+    pub fn multi_value_types() -> Vec<FuncType> {
+        let ty = |params, results| FuncType { params, results };
+        vec![
+            ty(vec![], vec![ValType::I32; 2]),
+            ty(vec![ValType::I32; 2], vec![ValType::I32; 2]),
+            ty(vec![ValType::I32], vec![ValType::I32]),
+            ty(vec![ValType::I32; 2], vec![ValType::I32]),
+        ]
+    }
+
+    /// Blocks with params and multiple results, run by each interpreter as a
+    /// function of type 0 of `multi_value_types` with two i32 locals:
+    /// (name, body, expected).
+    pub fn multi_value_cases() -> Vec<(&'static str, Vec<Instr>, Vec<Val>)> {
+        use Instr::*;
+        vec![
+            (
+                "block params",
+                vec![
+                    I32Const(1),
+                    I32Const(2),
+                    Block(
+                        BlockType::Index(1),
+                        vec![LocalSet(0), LocalSet(1), LocalGet(0), LocalGet(1)],
+                    ),
+                ],
+                vec![Val::I32(2), Val::I32(1)],
+            ),
+            (
+                "block params above operands",
+                vec![
+                    I32Const(5),
+                    I32Const(1),
+                    I32Const(2),
+                    Block(BlockType::Index(3), vec![I32Add]),
+                ],
+                vec![Val::I32(5), Val::I32(3)],
+            ),
+            (
+                "loop param",
+                vec![
+                    I32Const(7),
+                    I32Const(0),
+                    Loop(
+                        BlockType::Index(2),
+                        vec![
+                            I32Const(1),
+                            I32Add,
+                            LocalTee(0),
+                            LocalGet(0),
+                            I32Const(10),
+                            I32LtS,
+                            BrIf(0),
+                        ],
+                    ),
+                ],
+                vec![Val::I32(7), Val::I32(10)],
+            ),
+            (
+                "if params",
+                vec![
+                    I32Const(3),
+                    I32Const(4),
+                    I32Const(1),
+                    If(
+                        BlockType::Index(1),
+                        vec![I32Add, I32Const(0)],
+                        vec![I32Sub, I32Const(1)],
+                    ),
+                ],
+                vec![Val::I32(7), Val::I32(0)],
+            ),
+            (
+                "else params",
+                vec![
+                    I32Const(3),
+                    I32Const(4),
+                    I32Const(0),
+                    If(
+                        BlockType::Index(1),
+                        vec![I32Add, I32Const(0)],
+                        vec![I32Sub, I32Const(1)],
+                    ),
+                ],
+                vec![Val::I32(-1), Val::I32(1)],
+            ),
+            (
+                "br results",
+                vec![Block(
+                    BlockType::Index(0),
+                    vec![I32Const(1), I32Const(2), I32Const(3), Br(0)],
+                )],
+                vec![Val::I32(2), Val::I32(3)],
+            ),
+            (
+                "br_if results",
+                vec![Block(
+                    BlockType::Index(0),
+                    vec![
+                        I32Const(1),
+                        I32Const(2),
+                        I32Const(1),
+                        BrIf(0),
+                        Drop,
+                        Drop,
+                        I32Const(3),
+                        I32Const(4),
+                    ],
+                )],
+                vec![Val::I32(1), Val::I32(2)],
+            ),
+            (
+                "return from block with params",
+                vec![
+                    I32Const(8),
+                    I32Const(9),
+                    Block(BlockType::Index(1), vec![Return]),
+                ],
+                vec![Val::I32(8), Val::I32(9)],
+            ),
+        ]
+    }
+
+    /// A store with host functions `log` (logging a string from the caller's
+    /// memory), `bump` (adding to the caller's `counter` global) and `fail`
+    /// (trapping), imported by an instance whose function 3 `[] -> [i32]` logs
+    /// "hello" and bumps by 10, and whose function 4 calls `fail`.
+    pub fn host_store(log: Rc<std::cell::RefCell<Vec<String>>>) -> Store {
+        let mut store = Store::new();
+        let ty = |params, results| FuncType { params, results };
+        let types = vec![
+            ty(vec![ValType::I32, ValType::I32], vec![]),
+            ty(vec![ValType::I32], vec![ValType::I32]),
+            ty(vec![], vec![]),
+            ty(vec![], vec![ValType::I32]),
+        ];
+        let log_fn = HostFunc::new("log", move |caller, args| {
+            let [Val::I32(ptr), Val::I32(len)] = args else {
+                unreachable!()
+            };
+            let view = caller
+                .memory_view("memory")
+                .ok_or(TrapKind::MemoryOutOfBounds)?;
+            log.borrow_mut()
+                .push(view.str(*ptr as u32, *len as u32)?.to_string());
+            Ok(vec![])
+        });
+        let bump_fn = HostFunc::new("bump", |caller, args| {
+            let (Some(Val::I32(n)), [Val::I32(delta)]) = (caller.global("counter"), args) else {
+                unreachable!()
+            };
+            caller
+                .set_global("counter", Val::I32(n + delta))
+                .map_err(TrapKind::Host)?;
+            Ok(vec![Val::I32(n + delta)])
+        });
+        let fail_fn = HostFunc::new("fail", |_, _| Err(TrapKind::Host("boom".to_string())));
+        let imports = [
+            func_alloc_host(&mut store, types[0].clone(), log_fn),
+            func_alloc_host(&mut store, types[1].clone(), bump_fn),
+            func_alloc_host(&mut store, types[2].clone(), fail_fn),
+        ];
+
+        let mut module = Module::new();
+        module.types = types;
+        module.imports = ["log", "bump", "fail"]
+            .iter()
+            .zip(0..)
+            .map(|(name, ty)| Import {
+                module: "env".to_string(),
+                name: name.to_string(),
+                desc: ImportDesc::Func(ty),
+            })
+            .collect();
+        module.functions = vec![3, 2];
+        let code = |instrs| Code {
+            locals: vec![],
+            body: Expr { instrs },
+        };
+        module.code = vec![
+            code(vec![
+                Instr::I32Const(0),
+                Instr::I32Const(5),
+                Instr::Call(0),
+                Instr::I32Const(10),
+                Instr::Call(1),
+            ]),
+            code(vec![Instr::Nop, Instr::Call(2)]),
+        ];
+        module.memories = vec![Memory {
+            mem_type: MemType {
+                limits: Limits { min: 1, max: None },
+            },
+        }];
+        module.globals = vec![Global {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability: Mutability::Var,
+            },
+            init_expr: Expr {
+                instrs: vec![Instr::I32Const(1)],
+            },
+        }];
+        module.data = vec![Data {
+            init: b"hello".to_vec(),
+            mode: DataMode::Active {
+                memory: 0,
+                offset: Expr {
+                    instrs: vec![Instr::I32Const(0)],
+                },
+            },
+        }];
+        module.exports = vec![
+            Export {
+                name: "memory".to_string(),
+                desc: ExportDesc::Memory(0),
+            },
+            Export {
+                name: "counter".to_string(),
+                desc: ExportDesc::Global(0),
+            },
+        ];
+        let imports = imports.map(ExternVal::Func);
+        module_instantiate(&mut store, &module, &imports).unwrap();
+        store
+    }
+
+    /// Loop-heavy functions for benchmarking the interpreters: 0 `[i32] -> [i64]`
+    /// sums `0..n` in a loop, 1 `[i32] -> [i32]` runs `n` iterations each
+    /// leaving nested blocks through `br_table`, 2 `[i32] -> [i32]` is a
+    /// recursive fibonacci.
+    pub fn loop_store() -> Store {
+        use Instr::*;
+        let ty = |params, results| FuncType { params, results };
+        let types = vec![
+            ty(vec![ValType::I32], vec![ValType::I64]),
+            ty(vec![ValType::I32], vec![ValType::I32]),
+        ];
+        // `block (loop (br_if 1 (i >= n)) ..body (i += 1) (br 0))` over local 1
+        let count = |body: Vec<Instr>| {
+            let mut instrs = vec![LocalGet(1), LocalGet(0), I32GeS, BrIf(1)];
+            instrs.extend(body);
+            instrs.extend([LocalGet(1), I32Const(1), I32Add, LocalSet(1), Br(0)]);
+            Block(BlockType::Empty, vec![Loop(BlockType::Empty, instrs)])
+        };
+        let sum = vec![
+            count(vec![
+                LocalGet(2),
+                LocalGet(1),
+                I64ExtendI32S,
+                I64Add,
+                LocalSet(2),
+            ]),
+            LocalGet(2),
+        ];
+        let nested = vec![
+            count(vec![Block(
+                BlockType::Empty,
+                vec![Block(
+                    BlockType::Empty,
+                    vec![Block(
+                        BlockType::Empty,
+                        vec![LocalGet(1), I32Const(3), I32And, BrTable(vec![0, 1], 2)],
+                    )],
+                )],
+            )]),
+            LocalGet(1),
+        ];
+        let fib = vec![
+            LocalGet(0),
+            I32Const(2),
+            I32LtS,
+            If(
+                BlockType::ValTy(ValType::I32),
+                vec![LocalGet(0)],
+                vec![
+                    LocalGet(0),
+                    I32Const(1),
+                    I32Sub,
+                    Call(2),
+                    LocalGet(0),
+                    I32Const(2),
+                    I32Sub,
+                    Call(2),
+                    I32Add,
+                ],
+            ),
+        ];
+        store_with_funcs(
+            types,
+            vec![
+                (0, vec![ValType::I32, ValType::I64], sum),
+                (1, vec![ValType::I32], nested),
+                (1, vec![], fib),
+            ],
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ty(params: Vec<ValType>, results: Vec<ValType>) -> FuncType {
+        FuncType { params, results }
+    }
+
+    fn code(instrs: Vec<Instr>) -> Code {
+        Code {
+            locals: vec![],
+            body: Expr { instrs },
+        }
+    }
+
+    fn const_expr(i: i32) -> Expr {
+        Expr {
+            instrs: vec![Instr::I32Const(i)],
+        }
+    }
+
+    fn memory(min: u32, max: Option<u32>) -> Memory {
+        Memory {
+            mem_type: MemType {
+                limits: Limits { min, max },
+            },
+        }
+    }
+
+    fn active_data(offset: i32, init: Vec<u8>) -> Data {
+        Data {
+            init,
+            mode: DataMode::Active {
+                memory: 0,
+                offset: const_expr(offset),
+            },
+        }
+    }
+
+    #[test]
+    fn test_instantiate() {
+        let mut module = Module::new();
+        module.types = vec![ty(vec![], vec![]), ty(vec![], vec![ValType::I32])];
+        module.functions = vec![0, 1];
+        module.code = vec![
+            code(vec![
+                Instr::GlobalGet(0),
+                Instr::I32Const(1),
+                Instr::I32Add,
+                Instr::GlobalSet(0),
+            ]),
+            code(vec![
+                Instr::I32Const(8),
+                Instr::I32Load(MemArg {
+                    offset: 0,
+                    align: 2,
+                }),
+            ]),
+        ];
+        module.tables = vec![Table {
+            table_type: TableType {
+                limits: Limits { min: 2, max: None },
+                elem_type: RefType::FuncRef,
+            },
+        }];
+        module.memories = vec![memory(1, None)];
+        module.globals = vec![Global {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability: Mutability::Var,
+            },
+            init_expr: const_expr(41),
+        }];
+        let ref_func = Expr {
+            instrs: vec![Instr::RefFunc(1)],
+        };
+        module.elements = vec![
+            Element {
+                elem_type: RefType::FuncRef,
+                init: vec![ref_func.clone()],
+                mode: ElemMode::Active {
+                    table: 0,
+                    offset: const_expr(1),
+                },
+            },
+            Element {
+                elem_type: RefType::FuncRef,
+                init: vec![ref_func],
+                mode: ElemMode::Declarative,
+            },
+        ];
+        module.data = vec![
+            active_data(8, vec![1, 0, 0, 0]),
+            Data {
+                init: vec![9],
+                mode: DataMode::Passive,
+            },
+        ];
+        module.exports = vec![
+            Export {
+                name: "get".to_string(),
+                desc: ExportDesc::Func(1),
+            },
+            Export {
+                name: "g".to_string(),
+                desc: ExportDesc::Global(0),
+            },
+        ];
+        module.start = Some(Start { func_idx: 0 });
+
+        let mut store = Store::new();
+        // something allocated before, so addresses differ from indices
+        func_alloc_host(
+            &mut store,
+            ty(vec![], vec![]),
+            HostFunc::new("nop", |_, _| Ok(vec![])),
+        );
+        let inst = module_instantiate(&mut store, &module, &[]).unwrap();
+        assert_eq!(vec![1, 2], inst.func_addrs);
+        assert_eq!(
+            vec![
+                ExportInst {
+                    name: "get".to_string(),
+                    value: ExternVal::Func(2),
+                },
+                ExportInst {
+                    name: "g".to_string(),
+                    value: ExternVal::Global(0),
+                },
+            ],
+            inst.exports
+        );
+        // the start function ran after the init expr
+        assert_eq!(Val::I32(42), global_read(&store, 0));
+        assert_eq!(Ok(&[1, 0, 0, 0][..]), mem_read(&store, 0, 8, 4));
+        assert_eq!(Ok(Ref::Func(2)), table_read(&store, 0, 1));
+        assert_eq!(Ok(Ref::Null(RefType::FuncRef)), table_read(&store, 0, 0));
+        // active and declarative segments are dropped, passive ones kept
+        assert!(store.elems.iter().all(|e| e.elem.is_empty()));
+        assert_eq!(
+            vec![vec![], vec![9]],
+            store
+                .datas
+                .iter()
+                .map(|d| d.data.clone())
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(Ok(vec![Val::I32(1)]), func_invoke(&mut store, 2, &[]));
+    }
+
+    #[test]
+    fn test_instantiate_import_mismatch() {
+        let mut store = Store::new();
+        let f = func_alloc_host(
+            &mut store,
+            ty(vec![], vec![]),
+            HostFunc::new("f", |_, _| Ok(vec![])),
+        );
+        let mut module = Module::new();
+        module.types = vec![ty(vec![], vec![ValType::I32])];
+        module.imports = vec![Import {
+            module: "env".to_string(),
+            name: "f".to_string(),
+            desc: ImportDesc::Func(0),
+        }];
+        assert!(module_instantiate(&mut store, &module, &[]).is_err());
+        assert_eq!(
+            "incompatible import type for env.f",
+            module_instantiate(&mut store, &module, &[ExternVal::Func(f)]).unwrap_err()
+        );
+        assert!(store.modules.is_empty());
+    }
+
+    #[test]
+    fn test_instantiate_shared_memory() {
+        let mut store = Store::new();
+        let mut exporter = Module::new();
+        exporter.memories = vec![memory(1, Some(2))];
+        exporter.exports = vec![Export {
+            name: "mem".to_string(),
+            desc: ExportDesc::Memory(0),
+        }];
+        let mem = module_instantiate(&mut store, &exporter, &[])
+            .unwrap()
+            .exports[0]
+            .value
+            .clone();
+
+        let importer = |max| {
+            let mut module = Module::new();
+            module.imports = vec![Import {
+                module: "a".to_string(),
+                name: "mem".to_string(),
+                desc: ImportDesc::Memory(MemType {
+                    limits: Limits { min: 1, max },
+                }),
+            }];
+            module.data = vec![active_data(3, vec![7])];
+            module
+        };
+        // the memory might grow past a max of 1
+        assert!(
+            module_instantiate(&mut store, &importer(Some(1)), std::slice::from_ref(&mem)).is_err()
+        );
+        module_instantiate(&mut store, &importer(Some(3)), &[mem]).unwrap();
+        assert_eq!(Ok(&[7][..]), mem_read(&store, 0, 3, 1));
+    }
+
+    #[test]
+    fn test_instantiate_segment_trap() {
+        let mut module = Module::new();
+        module.memories = vec![memory(1, None)];
+        module.data = vec![
+            active_data(0, vec![1]),
+            active_data(PAGE_SIZE as i32, vec![2]),
+            active_data(1, vec![3]),
+        ];
+        let mut store = Store::new();
+        assert_eq!(
+            "out of bounds memory access",
+            module_instantiate(&mut store, &module, &[]).unwrap_err()
+        );
+        // segments before the trapping one were written
+        assert_eq!(Ok(&[1, 0][..]), mem_read(&store, 0, 0, 2));
+    }
+
+    #[test]
+    fn test_instantiate_limits() {
+        let instantiate = |store: &mut Store, memory| {
+            let mut module = Module::new();
+            module.memories = vec![memory];
+            module_instantiate(store, &module, &[]).map(|_| ())
+        };
+        let mut store = Store::new();
+        store.max_mem_pages = Some(1);
+        let err = Err("memory size limit exceeded".to_string());
+        assert_eq!(err, instantiate(&mut store, memory(4, None)));
+        assert_eq!(err, instantiate(&mut store, memory(2, Some(1))));
+        store.max_mem_pages = None;
+        assert_eq!(err, instantiate(&mut store, memory(u32::MAX, None)));
+        instantiate(&mut store, memory(1, Some(2))).unwrap();
+        let mem = store.mems.last().unwrap();
+        assert_eq!((PAGE_SIZE, 1), (mem.data.len(), mem.mem_type.limits.min));
+
+        let mut module = Module::new();
+        module.tables = vec![Table {
+            table_type: TableType {
+                limits: Limits {
+                    min: 3,
+                    max: Some(2),
+                },
+                elem_type: RefType::FuncRef,
+            },
+        }];
+        assert_eq!(
+            "table size limit exceeded",
+            module_instantiate(&mut store, &module, &[]).unwrap_err()
+        );
+    }
+
+    #[test]
+    fn test_instantiate_unknown_index() {
+        // nothing validates modules, so instantiating checks the indices it uses
+        let instantiate = |edit: &dyn Fn(&mut Module)| {
+            let mut module = Module::new();
+            edit(&mut module);
+            module_instantiate(&mut Store::new(), &module, &[]).unwrap_err()
+        };
+        let global = |init_expr| Global {
+            global_type: GlobalType {
+                value_type: ValType::I32,
+                mutability: Mutability::Const,
+            },
+            init_expr,
+        };
+        // globals see only the imported ones
+        assert_eq!(
+            "unknown global 0",
+            instantiate(&|m| {
+                m.globals = vec![
+                    global(const_expr(1)),
+                    global(Expr {
+                        instrs: vec![Instr::GlobalGet(0)],
+                    }),
+                ]
+            })
+        );
+        assert_eq!(
+            "unknown function 0",
+            instantiate(&|m| {
+                m.elements = vec![Element {
+                    elem_type: RefType::FuncRef,
+                    init: vec![Expr {
+                        instrs: vec![Instr::RefFunc(0)],
+                    }],
+                    mode: ElemMode::Passive,
+                }]
+            })
+        );
+        assert_eq!(
+            "unknown table 0",
+            instantiate(&|m| {
+                m.elements = vec![Element {
+                    elem_type: RefType::FuncRef,
+                    init: vec![],
+                    mode: ElemMode::Active {
+                        table: 0,
+                        offset: const_expr(0),
+                    },
+                }]
+            })
+        );
+        assert_eq!(
+            "unknown memory 0",
+            instantiate(&|m| m.data = vec![active_data(0, vec![1])])
+        );
+        assert_eq!(
+            "unknown global 2",
+            instantiate(&|m| {
+                m.exports = vec![Export {
+                    name: "g".to_string(),
+                    desc: ExportDesc::Global(2),
+                }]
+            })
+        );
+        assert_eq!(
+            "unknown function 0",
+            instantiate(&|m| m.start = Some(Start { func_idx: 0 }))
+        );
+    }
+
+    #[test]
+    fn test_func_invoke() {
+        let i32_div = ty(vec![ValType::I32, ValType::I32], vec![ValType::I32]);
+        let body = vec![Instr::LocalGet(0), Instr::LocalGet(1), Instr::I32DivS];
+        let mut store = testing::store_with_funcs(vec![i32_div], vec![(0, vec![], body)]);
+        assert_eq!(
+            Ok(vec![Val::I32(-3)]),
+            func_invoke(&mut store, 0, &[Val::I32(-7), Val::I32(2)])
+        );
+        assert_eq!(
+            Err("trap: integer divide by zero in func 0 at [2]".to_string()),
+            func_invoke(&mut store, 0, &[Val::I32(1), Val::I32(0)])
+        );
+        assert_eq!(
+            Err("expected 2 arguments, got 1".to_string()),
+            func_invoke(&mut store, 0, &[Val::I32(1)])
+        );
+        assert_eq!(
+            Err("argument 1 has type I64, expected I32".to_string()),
+            func_invoke(&mut store, 0, &[Val::I32(1), Val::I64(1)])
+        );
+        assert_eq!(
+            Err("unknown function 1".to_string()),
+            func_invoke(&mut store, 1, &[])
+        );
+    }
+
+    #[test]
+    fn test_func_invoke_host() {
+        let log = Rc::new(std::cell::RefCell::new(vec![]));
+        let mut store = testing::host_store(log.clone());
+        assert_eq!(Ok(vec![Val::I32(11)]), func_invoke(&mut store, 3, &[]));
+        assert_eq!(Val::I32(11), global_read(&store, 0));
+        // no calling instance, so no exported memory
+        assert_eq!(
+            Err("trap: out of bounds memory access in func 0 at []".to_string()),
+            func_invoke(&mut store, 0, &[Val::I32(0), Val::I32(1)])
+        );
+        assert_eq!(
+            Err("trap: boom in func 4 at [1]".to_string()),
+            func_invoke(&mut store, 4, &[])
+        );
+        assert_eq!(vec!["hello".to_string()], *log.borrow());
+    }
+
+    #[test]
+    fn test_func_invoke_reentrant() {
+        // guest func 1 calls host func 0, which invokes func 1 again
+        let mut store = Store::new();
+        let unit = ty(vec![], vec![]);
+        let reenter = HostFunc::new("reenter", |caller, _| {
+            func_invoke(caller.store, 1, &[]).map_err(TrapKind::Host)
+        });
+        func_alloc_host(&mut store, unit.clone(), reenter);
+        func_alloc(&mut store, unit.clone(), 0, code(vec![Instr::Call(0)]));
+        store.modules.push(ModuleInst {
+            types: vec![unit],
+            func_addrs: vec![0, 1],
+            ..Default::default()
+        });
+        let funcs = Rc::clone(&store.funcs);
+        // the depth carries over into the nested invocations, which give up
+        // long before the native stack does
+        let err = func_invoke(&mut store, 1, &[]).unwrap_err();
+        assert!(err.contains("call stack exhausted"), "{}", err);
+        assert_eq!((0, 0), (store.call_depth, store.host_depth));
+        // frames of suspended invocations count against nested ones
+        store.call_depth = store.max_call_depth - 1;
+        assert_eq!(
+            Err("trap: call stack exhausted in func 1 at [0]".to_string()),
+            func_invoke(&mut store, 1, &[])
+        );
+        store.call_depth = 0;
+        // so is the limit
+        store.max_call_depth = 1;
+        assert_eq!(
+            Err("trap: call stack exhausted in func 1 at [0]".to_string()),
+            func_invoke(&mut store, 1, &[])
+        );
+        // invoking shares the function table instead of copying it
+        assert!(Rc::ptr_eq(&funcs, &store.funcs));
+    }
+
+    #[test]
+    fn test_call_func_allocated_during_run() {
+        // host func 0 puts a new guest and a new host function in the table,
+        // which guest func 1 then calls
+        let unit = ty(vec![], vec![]);
+        let konst = ty(vec![], vec![ValType::I32]);
+        let mut store = Store::new();
+        let new_ty = konst.clone();
+        let make = HostFunc::new("make", move |caller, _| {
+            let store = &mut *caller.store;
+            let seven = code(vec![Instr::I32Const(7)]);
+            let guest = func_alloc(store, new_ty.clone(), 0, seven);
+            let eight = HostFunc::new("eight", |_, _| Ok(vec![Val::I32(8)]));
+            let host = func_alloc_host(store, new_ty.clone(), eight);
+            table_write(store, 0, 0, Ref::Func(guest)).map_err(TrapKind::Host)?;
+            table_write(store, 0, 1, Ref::Func(host)).map_err(TrapKind::Host)?;
+            Ok(vec![])
+        });
+        func_alloc_host(&mut store, unit.clone(), make);
+        let body = vec![
+            Instr::Call(0),
+            Instr::I32Const(0),
+            Instr::CallIndirect(0, 1),
+            Instr::I32Const(1),
+            Instr::CallIndirect(0, 1),
+            Instr::I32Add,
+        ];
+        func_alloc(&mut store, konst.clone(), 0, code(body));
+        store.modules.push(ModuleInst {
+            types: vec![unit, konst],
+            func_addrs: vec![0, 1],
+            ..Default::default()
+        });
+        testing::with_table(&mut store, vec![Ref::Null(RefType::FuncRef); 2]);
+        assert_eq!(Ok(vec![Val::I32(15)]), func_invoke(&mut store, 1, &[]));
+        store.cross_check = true;
+        assert_eq!(Ok(vec![Val::I32(15)]), func_invoke(&mut store, 1, &[]));
+        assert_eq!(6, store.funcs.len());
+        assert_eq!((0, 0), (store.call_depth, store.host_depth));
+    }
+}
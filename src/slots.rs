@@ -0,0 +1,242 @@
+//! Untagged operands: every value held in a raw 128-bit slot, its type known
+//! only from the code, which validation guarantees.
+
+use crate::exec::Operands;
+use crate::module::Ref;
+use crate::trap::TrapKind;
+use crate::types::{RefType, Val, ValType};
+
+pub type Slot = u128;
+
+// references keep their kind in the bits above the address
+const REF_FUNC: Slot = 0;
+const REF_EXTERN: Slot = 1 << 32;
+const REF_NULL_FUNC: Slot = 2 << 32;
+const REF_NULL_EXTERN: Slot = 3 << 32;
+const REF_KIND: Slot = 3 << 32;
+
+fn ref_to_slot(r: &Ref) -> Slot {
+    match r {
+        Ref::Func(addr) => REF_FUNC | *addr as Slot,
+        Ref::Extern(addr) => REF_EXTERN | *addr as Slot,
+        Ref::Null(RefType::FuncRef) => REF_NULL_FUNC,
+        Ref::Null(RefType::ExternRef) => REF_NULL_EXTERN,
+    }
+}
+
+fn slot_to_ref(slot: Slot) -> Ref {
+    match slot & REF_KIND {
+        REF_FUNC => Ref::Func(slot as u32),
+        REF_EXTERN => Ref::Extern(slot as u32),
+        REF_NULL_FUNC => Ref::Null(RefType::FuncRef),
+        _ => Ref::Null(RefType::ExternRef),
+    }
+}
+
+pub fn to_slot(v: &Val) -> Slot {
+    match v {
+        Val::I32(v) => *v as u32 as Slot,
+        Val::I64(v) => *v as u64 as Slot,
+        Val::F32(v) => v.to_bits() as Slot,
+        Val::F64(v) => v.to_bits() as Slot,
+        Val::V128(v) => *v,
+        Val::Ref(r) => ref_to_slot(r),
+    }
+}
+
+pub fn from_slot(slot: Slot, ty: &ValType) -> Val {
+    match ty {
+        ValType::I32 => Val::I32(slot as u32 as i32),
+        ValType::I64 => Val::I64(slot as u64 as i64),
+        ValType::F32 => Val::F32(f32::from_bits(slot as u32)),
+        ValType::F64 => Val::F64(f64::from_bits(slot as u64)),
+        ValType::V128 => Val::V128(slot),
+        ValType::FuncRef | ValType::ExternRef => Val::Ref(slot_to_ref(slot)),
+    }
+}
+
+/// How an engine holds values on its stacks and in its locals: tagged as a
+/// `Val`, or as an untagged `Slot` read back by the type the code expects.
+pub trait Repr: Clone {
+    fn from_val(v: &Val) -> Self;
+    /// Fails with `TypeMismatch` if the value is tagged with another type
+    fn into_val(self, ty: &ValType) -> Result<Val, TrapKind>;
+}
+
+impl Repr for Val {
+    #[inline]
+    fn from_val(v: &Val) -> Self {
+        v.clone()
+    }
+
+    #[inline]
+    fn into_val(self, ty: &ValType) -> Result<Val, TrapKind> {
+        if self.val_type() != *ty {
+            return Err(TrapKind::TypeMismatch);
+        }
+        Ok(self)
+    }
+}
+
+impl Repr for Slot {
+    #[inline]
+    fn from_val(v: &Val) -> Self {
+        to_slot(v)
+    }
+
+    #[inline]
+    fn into_val(self, ty: &ValType) -> Result<Val, TrapKind> {
+        Ok(from_slot(self, ty))
+    }
+}
+
+/// Pop the top `tys.len()` values as values of types `tys`.
+pub fn pop_typed_vals<V: Repr>(stack: &mut Vec<V>, tys: &[ValType]) -> Result<Vec<Val>, TrapKind> {
+    let start = stack
+        .len()
+        .checked_sub(tys.len())
+        .ok_or(TrapKind::StackUnderflow)?;
+    stack
+        .drain(start..)
+        .zip(tys)
+        .map(|(v, ty)| v.into_val(ty))
+        .collect()
+}
+
+fn pop(stack: &mut Vec<Slot>) -> Result<Slot, TrapKind> {
+    stack.pop().ok_or(TrapKind::StackUnderflow)
+}
+
+impl Operands for Vec<Slot> {
+    #[inline]
+    fn push_val(&mut self, v: Val) {
+        self.push(to_slot(&v))
+    }
+
+    #[inline]
+    fn pop_typed(&mut self, ty: &ValType) -> Result<Val, TrapKind> {
+        Ok(from_slot(pop(self)?, ty))
+    }
+
+    #[inline]
+    fn drop_val(&mut self) -> Result<(), TrapKind> {
+        pop(self).map(drop)
+    }
+
+    #[inline]
+    fn select_val(&mut self, first: bool) -> Result<(), TrapKind> {
+        let v2 = pop(self)?;
+        let v1 = self.last_mut().ok_or(TrapKind::StackUnderflow)?;
+        if !first {
+            *v1 = v2;
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn push_i32(&mut self, v: i32) {
+        self.push(v as u32 as Slot)
+    }
+
+    #[inline]
+    fn pop_i32(&mut self) -> Result<i32, TrapKind> {
+        pop(self).map(|slot| slot as u32 as i32)
+    }
+
+    #[inline]
+    fn push_i64(&mut self, v: i64) {
+        self.push(v as u64 as Slot)
+    }
+
+    #[inline]
+    fn pop_i64(&mut self) -> Result<i64, TrapKind> {
+        pop(self).map(|slot| slot as u64 as i64)
+    }
+
+    #[inline]
+    fn push_f32(&mut self, v: f32) {
+        self.push(v.to_bits() as Slot)
+    }
+
+    #[inline]
+    fn pop_f32(&mut self) -> Result<f32, TrapKind> {
+        pop(self).map(|slot| f32::from_bits(slot as u32))
+    }
+
+    #[inline]
+    fn push_f64(&mut self, v: f64) {
+        self.push(v.to_bits() as Slot)
+    }
+
+    #[inline]
+    fn pop_f64(&mut self) -> Result<f64, TrapKind> {
+        pop(self).map(|slot| f64::from_bits(slot as u64))
+    }
+
+    #[inline]
+    fn push_ref(&mut self, r: Ref) {
+        self.push(ref_to_slot(&r))
+    }
+
+    #[inline]
+    fn pop_ref(&mut self) -> Result<Ref, TrapKind> {
+        pop(self).map(slot_to_ref)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let vals = [
+            Val::I32(-1),
+            Val::I64(i64::MIN),
+            Val::F32(-0.5),
+            Val::F64(f64::MAX),
+            Val::V128(u128::MAX),
+            Val::Ref(Ref::Func(u32::MAX)),
+            Val::Ref(Ref::Extern(7)),
+            Val::Ref(Ref::Null(RefType::FuncRef)),
+            Val::Ref(Ref::Null(RefType::ExternRef)),
+        ];
+        for v in vals {
+            assert_eq!(v, from_slot(to_slot(&v), &v.val_type()));
+        }
+        // the upper bits of narrow values are clear
+        assert_eq!(0xffff_ffff, to_slot(&Val::I32(-1)));
+        let nan = f32::from_bits(0x7fc0_0001);
+        let Val::F32(v) = from_slot(to_slot(&Val::F32(nan)), &ValType::F32) else {
+            unreachable!()
+        };
+        assert_eq!(0x7fc0_0001, v.to_bits());
+    }
+
+    #[test]
+    fn test_operands() {
+        let mut stack: Vec<Slot> = vec![];
+        stack.push_i64(-2);
+        stack.push_i32(9);
+        stack.push_ref(Ref::Null(RefType::ExternRef));
+        stack.push_f32(1.5);
+        stack.push_i32(0);
+        let c = stack.pop_i32().unwrap();
+        assert_eq!(Ok(()), stack.select_val(c != 0));
+        assert_eq!(Ok(1.5), stack.pop_f32());
+        assert_eq!(Ok(()), stack.drop_val());
+        assert_eq!(Ok(Val::I64(-2)), stack.pop_typed(&ValType::I64));
+        assert_eq!(Err(TrapKind::StackUnderflow), stack.pop_i32());
+
+        stack.push_val(Val::I32(3));
+        stack.push_val(Val::F64(0.25));
+        assert_eq!(
+            Ok(vec![Val::I32(3), Val::F64(0.25)]),
+            pop_typed_vals(&mut stack, &[ValType::I32, ValType::F64])
+        );
+        assert_eq!(
+            Err(TrapKind::StackUnderflow),
+            pop_typed_vals(&mut stack, &[ValType::I32])
+        );
+    }
+}
@@ -0,0 +1,99 @@
+use std::fmt;
+
+use crate::module::FuncAddr;
+use crate::types::Instr;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrapKind {
+    Unreachable,
+    IntegerOverflow,
+    IntegerDivideByZero,
+    InvalidConversionToInteger,
+    MemoryOutOfBounds,
+    TableOutOfBounds,
+    UndefinedElement,
+    UninitializedElement,
+    IndirectCallTypeMismatch,
+    StackExhausted,
+    /// Raised by a host function
+    Host(String),
+    // The following can only be hit by code that skipped validation
+    StackUnderflow,
+    TypeMismatch,
+    UnknownLabel,
+    UnknownLocal,
+}
+
+impl fmt::Display for TrapKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // messages follow the spec test suite's assert_trap strings
+        let msg = match self {
+            TrapKind::Host(msg) => msg,
+            TrapKind::Unreachable => "unreachable",
+            TrapKind::IntegerOverflow => "integer overflow",
+            TrapKind::IntegerDivideByZero => "integer divide by zero",
+            TrapKind::InvalidConversionToInteger => "invalid conversion to integer",
+            TrapKind::MemoryOutOfBounds => "out of bounds memory access",
+            TrapKind::TableOutOfBounds => "out of bounds table access",
+            TrapKind::UndefinedElement => "undefined element",
+            TrapKind::UninitializedElement => "uninitialized element",
+            TrapKind::IndirectCallTypeMismatch => "indirect call type mismatch",
+            TrapKind::StackExhausted => "call stack exhausted",
+            TrapKind::StackUnderflow => "value stack underflow",
+            TrapKind::TypeMismatch => "type mismatch",
+            TrapKind::UnknownLabel => "unknown label",
+            TrapKind::UnknownLocal => "unknown local",
+        };
+        write!(f, "{}", msg)
+    }
+}
+
+/// A trap together with where it happened.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Trap {
+    pub kind: TrapKind,
+    /// The function being executed, `None` when running a bare instruction sequence
+    pub func: Option<FuncAddr>,
+    /// Index of the trapping instruction within each enclosing block, outermost first
+    pub pos: Vec<usize>,
+}
+
+impl Trap {
+    pub fn new(kind: TrapKind, func: Option<FuncAddr>, pos: Vec<usize>) -> Self {
+        Trap { kind, func, pos }
+    }
+}
+
+impl fmt::Display for Trap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "trap: {}", self.kind)?;
+        if let Some(func) = self.func {
+            write!(f, " in func {}", func)?;
+        }
+        write!(f, " at {:?}", self.pos)
+    }
+}
+
+impl std::error::Error for Trap {}
+
+/// Find the position of `target` (by address) within `instrs`, in the same
+/// form as `Trap::pos`.
+pub fn instr_pos(instrs: &[Instr], target: &Instr) -> Option<Vec<usize>> {
+    for (i, instr) in instrs.iter().enumerate() {
+        if std::ptr::eq(instr, target) {
+            return Some(vec![i]);
+        }
+        let found = match instr {
+            Instr::Block(_, es) | Instr::Loop(_, es) => instr_pos(es, target),
+            Instr::If(_, es_then, es_else) => {
+                instr_pos(es_then, target).or_else(|| instr_pos(es_else, target))
+            }
+            _ => None,
+        };
+        if let Some(mut pos) = found {
+            pos.insert(0, i);
+            return Some(pos);
+        }
+    }
+    None
+}
@@ -0,0 +1,310 @@
+//! Statically typed access to functions: Rust types standing in for wasm
+//! value types, so embedders don't build and match `Val`s by hand.
+
+use std::marker::PhantomData;
+
+use crate::module::{
+    func_alloc_host, func_invoke_unchecked, func_type, Caller, FuncAddr, HostFunc, Store,
+};
+use crate::trap::TrapKind;
+use crate::types::{FuncType, Val, ValType};
+
+/// A Rust type holding a single wasm value.
+pub trait WasmTy: Sized {
+    fn val_type() -> ValType;
+    fn into_val(self) -> Val;
+    fn from_val(val: &Val) -> Option<Self>;
+}
+
+macro_rules! wasm_ty {
+    ($ty:ty, $variant:ident) => {
+        impl WasmTy for $ty {
+            fn val_type() -> ValType {
+                ValType::$variant
+            }
+
+            fn into_val(self) -> Val {
+                Val::$variant(self)
+            }
+
+            fn from_val(val: &Val) -> Option<Self> {
+                match val {
+                    Val::$variant(v) => Some(*v),
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+wasm_ty!(i32, I32);
+wasm_ty!(i64, I64);
+wasm_ty!(f32, F32);
+wasm_ty!(f64, F64);
+
+/// A Rust type holding a sequence of wasm values: a single `WasmTy`, or a
+/// tuple of them for params and multiple results.
+pub trait WasmTys: Sized {
+    fn val_types() -> Vec<ValType>;
+    fn into_vals(self) -> Vec<Val>;
+    fn from_vals(vals: &[Val]) -> Option<Self>;
+}
+
+impl<T: WasmTy> WasmTys for T {
+    fn val_types() -> Vec<ValType> {
+        vec![T::val_type()]
+    }
+
+    fn into_vals(self) -> Vec<Val> {
+        vec![self.into_val()]
+    }
+
+    fn from_vals(vals: &[Val]) -> Option<Self> {
+        match vals {
+            [val] => T::from_val(val),
+            _ => None,
+        }
+    }
+}
+
+/// What a host closure may return: its results, or those results or a trap.
+pub trait HostResults {
+    type Results: WasmTys;
+    fn into_results(self) -> Result<Self::Results, TrapKind>;
+}
+
+impl<T: WasmTys> HostResults for T {
+    type Results = T;
+
+    fn into_results(self) -> Result<T, TrapKind> {
+        Ok(self)
+    }
+}
+
+impl<T: WasmTys> HostResults for Result<T, TrapKind> {
+    type Results = T;
+
+    fn into_results(self) -> Result<T, TrapKind> {
+        self
+    }
+}
+
+/// Marks closures taking the `Caller` as their first argument.
+pub struct WithCaller;
+
+/// A Rust closure usable as a host function. `Params` is only there to tell
+/// apart the impls for different arities.
+pub trait IntoHostFunc<Params, Results> {
+    fn func_type() -> FuncType;
+    fn into_host_func(self, name: &str) -> HostFunc;
+}
+
+macro_rules! tuples {
+    ($($t:ident)*) => {
+        #[allow(non_snake_case)]
+        impl<$($t: WasmTy),*> WasmTys for ($($t,)*) {
+            fn val_types() -> Vec<ValType> {
+                vec![$($t::val_type()),*]
+            }
+
+            fn into_vals(self) -> Vec<Val> {
+                let ($($t,)*) = self;
+                vec![$($t.into_val()),*]
+            }
+
+            fn from_vals(vals: &[Val]) -> Option<Self> {
+                let [$($t),*] = vals else {
+                    return None;
+                };
+                Some(($($t::from_val($t)?,)*))
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($t: WasmTy),*> IntoHostFunc<($($t,)*), R> for F
+        where
+            F: Fn($($t),*) -> R + 'static,
+            R: HostResults,
+        {
+            fn func_type() -> FuncType {
+                FuncType {
+                    params: <($($t,)*)>::val_types(),
+                    results: R::Results::val_types(),
+                }
+            }
+
+            fn into_host_func(self, name: &str) -> HostFunc {
+                HostFunc::new(name, move |_, args| {
+                    let ($($t,)*) = <($($t,)*)>::from_vals(args).ok_or(TrapKind::TypeMismatch)?;
+                    Ok(self($($t),*).into_results()?.into_vals())
+                })
+            }
+        }
+
+        #[allow(non_snake_case)]
+        impl<F, R, $($t: WasmTy),*> IntoHostFunc<(WithCaller, $($t,)*), R> for F
+        where
+            F: Fn(&mut Caller, $($t),*) -> R + 'static,
+            R: HostResults,
+        {
+            fn func_type() -> FuncType {
+                FuncType {
+                    params: <($($t,)*)>::val_types(),
+                    results: R::Results::val_types(),
+                }
+            }
+
+            fn into_host_func(self, name: &str) -> HostFunc {
+                HostFunc::new(name, move |caller, args| {
+                    let ($($t,)*) = <($($t,)*)>::from_vals(args).ok_or(TrapKind::TypeMismatch)?;
+                    Ok(self(caller, $($t),*).into_results()?.into_vals())
+                })
+            }
+        }
+    };
+}
+
+tuples!();
+tuples!(A1);
+tuples!(A1 A2);
+tuples!(A1 A2 A3);
+tuples!(A1 A2 A3 A4);
+tuples!(A1 A2 A3 A4 A5);
+tuples!(A1 A2 A3 A4 A5 A6);
+
+/// Allocate a host function backed by a Rust closure, its type derived from
+/// the closure's signature.
+pub fn func_wrap<P, R, F: IntoHostFunc<P, R>>(store: &mut Store, name: &str, func: F) -> FuncAddr {
+    func_alloc_host(store, F::func_type(), func.into_host_func(name))
+}
+
+/// A function whose type was checked against `Params` and `Results` once, so
+/// calls skip the argument checks of `func_invoke`.
+pub struct TypedFunc<Params, Results> {
+    addr: FuncAddr,
+    _ty: PhantomData<fn(Params) -> Results>,
+}
+
+impl<Params: WasmTys, Results: WasmTys> TypedFunc<Params, Results> {
+    pub fn new(store: &Store, addr: FuncAddr) -> Result<Self, String> {
+        if addr as usize >= store.funcs.len() {
+            return Err(format!("unknown function {}", addr));
+        }
+        let expected = FuncType {
+            params: Params::val_types(),
+            results: Results::val_types(),
+        };
+        let actual = func_type(store, addr);
+        if *actual != expected {
+            return Err(format!(
+                "function {} has type {:?}, expected {:?}",
+                addr, actual, expected
+            ));
+        }
+        Ok(TypedFunc {
+            addr,
+            _ty: PhantomData,
+        })
+    }
+
+    pub fn addr(&self) -> FuncAddr {
+        self.addr
+    }
+
+    pub fn call(&self, store: &mut Store, params: Params) -> Result<Results, String> {
+        let results = func_invoke_unchecked(store, self.addr, &params.into_vals())?;
+        // unvalidated code may still return the wrong types
+        Results::from_vals(&results)
+            .ok_or_else(|| format!("function {} returned {:?}", self.addr, results))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::module::testing::store_with_funcs;
+    use crate::types::Instr;
+
+    fn ty(params: Vec<ValType>, results: Vec<ValType>) -> FuncType {
+        FuncType { params, results }
+    }
+
+    #[test]
+    fn test_typed_guest_call() {
+        let mut store = store_with_funcs(
+            vec![
+                ty(vec![ValType::I32, ValType::I32], vec![ValType::I32]),
+                ty(vec![ValType::I64], vec![ValType::I64, ValType::F64]),
+            ],
+            vec![
+                (
+                    0,
+                    vec![],
+                    vec![Instr::LocalGet(0), Instr::LocalGet(1), Instr::I32Sub],
+                ),
+                (1, vec![], vec![Instr::LocalGet(0), Instr::F64Const(0.5)]),
+            ],
+        );
+        let sub = TypedFunc::<(i32, i32), i32>::new(&store, 0).unwrap();
+        assert_eq!(Ok(3), sub.call(&mut store, (5, 2)));
+        let pair = TypedFunc::<i64, (i64, f64)>::new(&store, 1).unwrap();
+        assert_eq!(Ok((7, 0.5)), pair.call(&mut store, 7));
+
+        assert_eq!(
+            "function 0 has type FuncType { params: [I32, I32], results: [I32] }, \
+             expected FuncType { params: [I32], results: [I32] }",
+            TypedFunc::<i32, i32>::new(&store, 0).err().unwrap()
+        );
+        assert_eq!(
+            "unknown function 2",
+            TypedFunc::<(), ()>::new(&store, 2).err().unwrap()
+        );
+    }
+
+    #[test]
+    fn test_func_wrap() {
+        let mut store = Store::new();
+        let add = func_wrap(&mut store, "add", |a: i32, b: i64| a as i64 + b);
+        assert_eq!(
+            ty(vec![ValType::I32, ValType::I64], vec![ValType::I64]),
+            *func_type(&store, add)
+        );
+        let add = TypedFunc::<(i32, i64), i64>::new(&store, add).unwrap();
+        assert_eq!(Ok(5), add.call(&mut store, (2, 3)));
+
+        let div = func_wrap(&mut store, "div", |a: f32, b: f32| {
+            if b == 0.0 {
+                Err(TrapKind::Host("division by zero".to_string()))
+            } else {
+                Ok((a / b, a * b))
+            }
+        });
+        let div = TypedFunc::<(f32, f32), (f32, f32)>::new(&store, div).unwrap();
+        assert_eq!(Ok((2.0, 8.0)), div.call(&mut store, (4.0, 2.0)));
+        assert_eq!(
+            Err("trap: division by zero in func 1 at []".to_string()),
+            div.call(&mut store, (4.0, 0.0))
+        );
+
+        let nop = func_wrap(&mut store, "nop", || {});
+        assert_eq!(ty(vec![], vec![]), *func_type(&store, nop));
+    }
+
+    #[test]
+    fn test_func_wrap_caller() {
+        // wasm passing its argument on to a host function that sees the caller
+        let mut store = store_with_funcs(
+            vec![ty(vec![ValType::I32], vec![ValType::I32])],
+            vec![(0, vec![], vec![Instr::LocalGet(0), Instr::Call(0)])],
+        );
+        let host = func_wrap(&mut store, "has_caller", |caller: &mut Caller, x: i32| {
+            x + caller.module.is_some() as i32
+        });
+        store.modules[0].func_addrs.insert(0, host);
+
+        let run = TypedFunc::<i32, i32>::new(&store, 0).unwrap();
+        assert_eq!(Ok(11), run.call(&mut store, 10));
+        let host = TypedFunc::<i32, i32>::new(&store, host).unwrap();
+        assert_eq!(Ok(10), host.call(&mut store, 10));
+    }
+}
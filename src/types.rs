@@ -1,5 +1,7 @@
 use std::ops::Index;
 
+use crate::module::Ref;
+
 // ============================================================================
 // Value Types
 // ============================================================================
@@ -21,14 +23,13 @@ pub enum RefType {
     ExternRef,
 }
 
-// Legacy Ty enum for existing VM code - will eventually migrate to ValType
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub enum Ty {
-    I32,
-    I64,
-    F32,
-    F64,
-    Func(Vec<Ty>, Vec<Ty>),
+impl From<RefType> for ValType {
+    fn from(ty: RefType) -> Self {
+        match ty {
+            RefType::FuncRef => ValType::FuncRef,
+            RefType::ExternRef => ValType::ExternRef,
+        }
+    }
 }
 
 // ============================================================================
@@ -98,15 +99,6 @@ pub enum ExternType {
     Global(GlobalType),
 }
 
-impl Ty {
-    pub fn func_tys(&self) -> (&[Ty], &[Ty]) {
-        match self {
-            Ty::Func(args, res) => (args, res),
-            _ => panic!("not a function type"),
-        }
-    }
-}
-
 // ============================================================================
 // Indices (type-safe wrappers)
 // ============================================================================
@@ -125,26 +117,225 @@ pub type LabelIdx = u32;
 pub enum BlockType {
     Empty,
     Index(usize),
-    ValTy(Ty),
+    ValTy(ValType),
 }
 
+/// Immediate of memory instructions. The alignment is a log2 hint with no
+/// effect on semantics.
 #[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemArg {
+    pub offset: u32,
+    pub align: u32,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum Instr {
     Unreachable,
     Nop,
-    I32Const(i32),
-    I32Add,
-    I32Sub,
-    I32Mul,
-    I32Eq,
     Drop,
+    /// The type is given for typed `select`
+    Select(Option<ValType>),
+    RefNull(RefType),
+    RefIsNull,
+    RefFunc(usize),
     LocalTee(usize),
     LocalGet(usize),
     LocalSet(usize),
     Br(usize),
+    BrIf(usize),
+    /// Labels indexed by the operand, default label
+    BrTable(Vec<usize>, usize),
+    Return,
+    Call(usize),
+    /// Table index, type index
+    CallIndirect(usize, usize),
     If(BlockType, Vec<Instr>, Vec<Instr>),
     Loop(BlockType, Vec<Instr>),
     Block(BlockType, Vec<Instr>),
+    GlobalGet(usize),
+    GlobalSet(usize),
+    // Table
+    TableGet(usize),
+    TableSet(usize),
+    TableSize(usize),
+    TableGrow(usize),
+    TableFill(usize),
+    /// Destination table index, source table index
+    TableCopy(usize, usize),
+    /// Table index, element segment index
+    TableInit(usize, usize),
+    ElemDrop(usize),
+    // Memory
+    I32Load(MemArg),
+    I64Load(MemArg),
+    F32Load(MemArg),
+    F64Load(MemArg),
+    I32Load8S(MemArg),
+    I32Load8U(MemArg),
+    I32Load16S(MemArg),
+    I32Load16U(MemArg),
+    I64Load8S(MemArg),
+    I64Load8U(MemArg),
+    I64Load16S(MemArg),
+    I64Load16U(MemArg),
+    I64Load32S(MemArg),
+    I64Load32U(MemArg),
+    I32Store(MemArg),
+    I64Store(MemArg),
+    F32Store(MemArg),
+    F64Store(MemArg),
+    I32Store8(MemArg),
+    I32Store16(MemArg),
+    I64Store8(MemArg),
+    I64Store16(MemArg),
+    I64Store32(MemArg),
+    MemorySize,
+    MemoryGrow,
+    MemoryFill,
+    MemoryCopy,
+    MemoryInit(usize),
+    DataDrop(usize),
+    // Numeric
+    I32Const(i32),
+    I32Eqz,
+    I32Eq,
+    I32Ne,
+    I32LtS,
+    I32LtU,
+    I32GtS,
+    I32GtU,
+    I32LeS,
+    I32LeU,
+    I32GeS,
+    I32GeU,
+    I32Clz,
+    I32Ctz,
+    I32Popcnt,
+    I32Add,
+    I32Sub,
+    I32Mul,
+    I32DivS,
+    I32DivU,
+    I32RemS,
+    I32RemU,
+    I32And,
+    I32Or,
+    I32Xor,
+    I32Shl,
+    I32ShrS,
+    I32ShrU,
+    I32Rotl,
+    I32Rotr,
+    I64Const(i64),
+    I64Eqz,
+    I64Eq,
+    I64Ne,
+    I64LtS,
+    I64LtU,
+    I64GtS,
+    I64GtU,
+    I64LeS,
+    I64LeU,
+    I64GeS,
+    I64GeU,
+    I64Clz,
+    I64Ctz,
+    I64Popcnt,
+    I64Add,
+    I64Sub,
+    I64Mul,
+    I64DivS,
+    I64DivU,
+    I64RemS,
+    I64RemU,
+    I64And,
+    I64Or,
+    I64Xor,
+    I64Shl,
+    I64ShrS,
+    I64ShrU,
+    I64Rotl,
+    I64Rotr,
+    F32Const(f32),
+    F32Eq,
+    F32Ne,
+    F32Lt,
+    F32Gt,
+    F32Le,
+    F32Ge,
+    F32Abs,
+    F32Neg,
+    F32Ceil,
+    F32Floor,
+    F32Trunc,
+    F32Nearest,
+    F32Sqrt,
+    F32Add,
+    F32Sub,
+    F32Mul,
+    F32Div,
+    F32Min,
+    F32Max,
+    F32Copysign,
+    F64Const(f64),
+    F64Eq,
+    F64Ne,
+    F64Lt,
+    F64Gt,
+    F64Le,
+    F64Ge,
+    F64Abs,
+    F64Neg,
+    F64Ceil,
+    F64Floor,
+    F64Trunc,
+    F64Nearest,
+    F64Sqrt,
+    F64Add,
+    F64Sub,
+    F64Mul,
+    F64Div,
+    F64Min,
+    F64Max,
+    F64Copysign,
+    I32WrapI64,
+    I32TruncF32S,
+    I32TruncF32U,
+    I32TruncF64S,
+    I32TruncF64U,
+    I64ExtendI32S,
+    I64ExtendI32U,
+    I64TruncF32S,
+    I64TruncF32U,
+    I64TruncF64S,
+    I64TruncF64U,
+    F32ConvertI32S,
+    F32ConvertI32U,
+    F32ConvertI64S,
+    F32ConvertI64U,
+    F32DemoteF64,
+    F64ConvertI32S,
+    F64ConvertI32U,
+    F64ConvertI64S,
+    F64ConvertI64U,
+    F64PromoteF32,
+    I32ReinterpretF32,
+    I64ReinterpretF64,
+    F32ReinterpretI32,
+    F64ReinterpretI64,
+    I32Extend8S,
+    I32Extend16S,
+    I64Extend8S,
+    I64Extend16S,
+    I64Extend32S,
+    I32TruncSatF32S,
+    I32TruncSatF32U,
+    I32TruncSatF64S,
+    I32TruncSatF64U,
+    I64TruncSatF32S,
+    I64TruncSatF32U,
+    I64TruncSatF64S,
+    I64TruncSatF64U,
 }
 
 #[derive(Debug, Clone, PartialEq)]
@@ -153,13 +344,47 @@ pub enum Val {
     I64(i64),
     F32(f32),
     F64(f64),
-    NULL(Ty),
+    V128(u128),
+    Ref(Ref),
+}
+
+impl Val {
+    /// The default value of a type: zero for numbers and vectors, null for references.
+    /// Used to initialize declared locals.
+    pub fn default_for(ty: &ValType) -> Val {
+        match ty {
+            ValType::I32 => Val::I32(0),
+            ValType::I64 => Val::I64(0),
+            ValType::F32 => Val::F32(0.0),
+            ValType::F64 => Val::F64(0.0),
+            ValType::V128 => Val::V128(0),
+            ValType::FuncRef => Val::Ref(Ref::Null(RefType::FuncRef)),
+            ValType::ExternRef => Val::Ref(Ref::Null(RefType::ExternRef)),
+        }
+    }
+
+    pub fn val_type(&self) -> ValType {
+        match self {
+            Val::I32(_) => ValType::I32,
+            Val::I64(_) => ValType::I64,
+            Val::F32(_) => ValType::F32,
+            Val::F64(_) => ValType::F64,
+            Val::V128(_) => ValType::V128,
+            Val::Ref(r) => r.ref_type().into(),
+        }
+    }
 }
 
-pub fn block_type<I: Index<usize, Output = Ty>>(ind_tys: &I, ty: &BlockType) -> Ty {
+pub fn block_type<I: Index<usize, Output = FuncType>>(ind_tys: &I, ty: &BlockType) -> FuncType {
     match ty {
-        BlockType::Empty => Ty::Func(vec![], vec![]),
+        BlockType::Empty => FuncType {
+            params: vec![],
+            results: vec![],
+        },
         BlockType::Index(i) => ind_tys[*i].clone(),
-        BlockType::ValTy(ty) => Ty::Func(vec![], vec![ty.clone()]),
+        BlockType::ValTy(ty) => FuncType {
+            params: vec![],
+            results: vec![ty.clone()],
+        },
     }
 }